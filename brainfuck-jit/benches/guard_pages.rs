@@ -0,0 +1,72 @@
+use bfrs::bfio::input_from;
+use bfrs::{BfVM, VmConfig};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+/// Programs that spend their time moving the pointer and touching cells, with
+/// their input. Each reads its loop counts first, so none of it can run at
+/// compile time.
+fn programs() -> Vec<(&'static str, String, Vec<u8>)> {
+    vec![
+        (
+            "walk",
+            format!(",[{}{}-]", ">+".repeat(500), "<".repeat(500)),
+            vec![50],
+        ),
+        (
+            "nested",
+            ",[>++++++++[>++++++++[>++++++++[>++++++++[>+>>+<<<-]>[-]<<-]<-]<-]<-]".to_string(),
+            vec![8],
+        ),
+        (
+            "shuffle",
+            ",[>[>>+<<-]>>[<+>-]<<<-]".repeat(50),
+            vec![200; 50],
+        ),
+    ]
+}
+
+fn bench_guard_pages(c: &mut Criterion) {
+    for (name, src, input) in programs() {
+        let mut group = c.benchmark_group(name);
+        for optimize in [false, true] {
+            for guard_pages in [false, true] {
+                let id = format!(
+                    "{}/{}",
+                    if optimize { "optimized" } else { "plain" },
+                    if guard_pages {
+                        "guard pages"
+                    } else {
+                        "bounds checks"
+                    }
+                );
+                let config = VmConfig::new()
+                    .tape_size(64 * 1024)
+                    .guard_pages(guard_pages);
+                group.bench_function(id, |b| {
+                    b.iter_batched(
+                        || {
+                            BfVM::from_source(
+                                &src,
+                                input_from(input.clone()),
+                                Box::new(std::io::sink()),
+                                optimize,
+                                config.clone(),
+                            )
+                            .unwrap()
+                        },
+                        |mut vm| {
+                            vm.run().unwrap();
+                            vm
+                        },
+                        BatchSize::SmallInput,
+                    )
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_guard_pages);
+criterion_main!(benches);
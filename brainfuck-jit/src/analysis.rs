@@ -0,0 +1,141 @@
+use crate::bfir::{self, BfIR};
+
+/// The cells `lo..=hi` the pointer may be on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PtrRange {
+    pub(crate) lo: i64,
+    pub(crate) hi: i64,
+}
+
+impl PtrRange {
+    fn shift(self, by: i64) -> Self {
+        Self {
+            lo: self.lo + by,
+            hi: self.hi + by,
+        }
+    }
+
+    /// Whether `ptr + lo..=ptr + hi` lies on a tape of `len` cells for every
+    /// `ptr` in the range.
+    pub(crate) fn covers(self, lo: i64, hi: i64, len: usize) -> bool {
+        self.lo + lo >= 0 && self.hi + hi < len as i64
+    }
+
+    /// Narrows the range to where `ptr + offset` lies on a tape of `len`
+    /// cells, as an op reaching further fails rather than going on.
+    fn fit(self, offset: i64, len: usize) -> Option<Self> {
+        let lo = self.lo.max(-offset);
+        let hi = self.hi.min(len as i64 - 1 - offset);
+        (lo <= hi).then_some(Self { lo, hi })
+    }
+}
+
+/// Runs `code` abstractly from `origin` on a tape of `len` cells, and
+/// returns the cells the pointer may be on as each op starts, and then as
+/// the program ends, or `None` where it never gets. The loops in `code` must
+/// be balanced.
+///
+/// A loop whose range keeps growing around its back edge is widened to the
+/// edge of the tape, so a few passes reach a fixed point.
+pub(crate) fn ptr_ranges(code: &[BfIR], origin: usize, len: usize) -> Vec<Option<PtrRange>> {
+    let jumps = bfir::jump_table(code);
+
+    let mut ranges = vec![None; code.len() + 1];
+    ranges[0] = Some(PtrRange {
+        lo: origin as i64,
+        hi: origin as i64,
+    });
+
+    let edge = len as i64 - 1;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in 0..code.len() {
+            let Some(r) = ranges[pc] else {
+                continue;
+            };
+            let mut flow = |to: usize, next: Option<PtrRange>, widen: bool| {
+                changed |= merge(&mut ranges[to], next, widen, edge);
+            };
+
+            use BfIR::*;
+            match code[pc] {
+                AddPtr(x) => flow(pc + 1, r.shift(x as i64).fit(0, len), false),
+                SubPtr(x) => flow(pc + 1, r.shift(-(x as i64)).fit(0, len), false),
+                AddVal { offset, .. }
+                | SubVal { offset, .. }
+                | GetByte { offset }
+                | PutByte { offset } => flow(pc + 1, r.fit(offset as i64, len), false),
+                // only reaches its target when the cell is non-zero
+                SetZero | SetValue(_) | MulAdd { .. } => flow(pc + 1, Some(r), false),
+                ScanRight(_) => flow(pc + 1, Some(PtrRange { lo: r.lo, hi: edge }), false),
+                ScanLeft(_) => flow(pc + 1, Some(PtrRange { lo: 0, hi: r.hi }), false),
+                Jz => {
+                    flow(pc + 1, Some(r), false);
+                    flow(jumps[pc] + 1, Some(r), false);
+                }
+                Jnz => {
+                    flow(pc + 1, Some(r), false);
+                    flow(jumps[pc] + 1, Some(r), true);
+                }
+            }
+        }
+    }
+
+    ranges
+}
+
+/// Joins `next` into `slot`, and returns whether `slot` grew. With `widen`,
+/// a side that grows jumps to the edge of the tape at `0..=edge`.
+fn merge(slot: &mut Option<PtrRange>, next: Option<PtrRange>, widen: bool, edge: i64) -> bool {
+    let Some(next) = next else {
+        return false;
+    };
+    let Some(old) = *slot else {
+        *slot = Some(next);
+        return true;
+    };
+
+    let mut joined = PtrRange {
+        lo: old.lo.min(next.lo),
+        hi: old.hi.max(next.hi),
+    };
+    if joined == old {
+        return false;
+    }
+    if widen {
+        if joined.lo < old.lo {
+            joined.lo = 0;
+        }
+        if joined.hi > old.hi {
+            joined.hi = edge;
+        }
+    }
+    *slot = Some(joined);
+    true
+}
+
+#[test]
+fn test_ptr_ranges() {
+    let range = |lo, hi| Some(PtrRange { lo, hi });
+
+    let code = crate::compile(">>[-<+>]<<.[>]").unwrap();
+    let ranges = ptr_ranges(&code, 0, 100);
+    // a balanced loop stays put, and `<<` cannot run off the left edge
+    assert_eq!(ranges[2], range(2, 2));
+    assert_eq!(ranges[8], range(2, 2));
+    assert_eq!(ranges[10], range(0, 0));
+    // each round of `[>]` may take it further right, up to the edge
+    assert_eq!(ranges[13], range(1, 99));
+
+    // a loop that drifts right widens to the edge
+    let code = crate::compile("+[>+]").unwrap();
+    let ranges = ptr_ranges(&code, 5, 100);
+    assert_eq!(ranges[2], range(5, 99));
+    assert_eq!(ranges[3], range(6, 99));
+
+    // nothing runs past a move that always falls off the tape
+    let code = crate::compile("<+").unwrap();
+    let ranges = ptr_ranges(&code, 0, 100);
+    assert_eq!(ranges, [range(0, 0), None, None]);
+}
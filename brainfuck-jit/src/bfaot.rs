@@ -0,0 +1,264 @@
+use crate::bfir::BfIR;
+use crate::bfjit::{check_loops, OutputBuffer};
+use crate::codegen::{self, Asm, Runtime, OUTPUT_DATA};
+use crate::config::{CellWidth, EofPolicy, VmConfig};
+use crate::error::{Result, VMError};
+
+use dynasm::dynasm;
+use dynasmrt::x64::X64Relocation;
+use dynasmrt::{DynasmLabelApi, VecAssembler};
+
+/// Address the executable is loaded at.
+const BASE: u64 = 0x400000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_EXIT: i32 = 60;
+const EINTR: i32 = 4;
+
+const OVERFLOW_MESSAGE: &[u8] = b"bfjit: Runtime: Pointer overflow\n";
+
+/// Compiles `ir` to a static x86-64 Linux executable that reads stdin,
+/// writes stdout and behaves like the JIT with the same `config`.
+///
+/// The executable is a single RWX segment. The output buffer and the tape
+/// live in the zero-filled memory past the end of the file, and I/O goes
+/// through raw system calls, so it depends on nothing at run time.
+pub fn build(ir: &[BfIR], config: &VmConfig) -> Result<Vec<u8>> {
+    config.validate().map_err(VMError::InvalidConfig)?;
+    check_loops(ir)?;
+
+    let tape_bytes = config.tape_bytes().unwrap();
+    let entry = BASE as usize + EHDR_SIZE + PHDR_SIZE;
+
+    let mut ops = VecAssembler::<X64Relocation>::new(entry);
+    let rt = AotRuntime {
+        width: config.cell_width,
+        eof: config.eof,
+        tape_bytes,
+        origin: config.tape_origin * config.cell_width.bytes(),
+    };
+    codegen::compile(&mut ops, &rt, ir, config);
+    // past the data the code ends with, too
+    dynasm!(ops
+        ; -> image_end:
+    );
+    let code = ops.finalize().unwrap();
+
+    let file_size = EHDR_SIZE + PHDR_SIZE + code.len();
+    // room to align the output buffer, which the tape follows
+    let mem_size = file_size + 16 + std::mem::size_of::<OutputBuffer>() + tape_bytes;
+
+    let mut elf = Vec::with_capacity(file_size);
+
+    // ELF header
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2_u16.to_le_bytes()); // e_type: ET_EXEC
+    elf.extend_from_slice(&0x3e_u16.to_le_bytes()); // e_machine: x86-64
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // e_version
+    elf.extend_from_slice(&(entry as u64).to_le_bytes()); // e_entry
+    elf.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+    elf.extend_from_slice(&0_u64.to_le_bytes()); // e_shoff
+    elf.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+    elf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes()); // e_ehsize
+    elf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&1_u16.to_le_bytes()); // e_phnum
+    elf.extend_from_slice(&64_u16.to_le_bytes()); // e_shentsize
+    elf.extend_from_slice(&0_u16.to_le_bytes()); // e_shnum
+    elf.extend_from_slice(&0_u16.to_le_bytes()); // e_shstrndx
+
+    // program header
+    elf.extend_from_slice(&1_u32.to_le_bytes()); // p_type: PT_LOAD
+    elf.extend_from_slice(&7_u32.to_le_bytes()); // p_flags: RWX
+    elf.extend_from_slice(&0_u64.to_le_bytes()); // p_offset
+    elf.extend_from_slice(&BASE.to_le_bytes()); // p_vaddr
+    elf.extend_from_slice(&BASE.to_le_bytes()); // p_paddr
+    elf.extend_from_slice(&(file_size as u64).to_le_bytes()); // p_filesz
+    elf.extend_from_slice(&(mem_size as u64).to_le_bytes()); // p_memsz
+    elf.extend_from_slice(&0x1000_u64.to_le_bytes()); // p_align
+
+    elf.extend_from_slice(&code);
+    Ok(elf)
+}
+
+/// Runs compiled code as the whole process, talking to the kernel directly.
+struct AotRuntime {
+    width: CellWidth,
+    eof: EofPolicy,
+    tape_bytes: usize,
+    /// Byte offset of the first cell the pointer is on.
+    origin: usize,
+}
+
+impl AotRuntime {
+    /// `*r12 = edx`
+    fn store_edx<D: Asm>(&self, ops: &mut D) {
+        match self.width {
+            CellWidth::U8 => dynasm!(ops
+                ; mov BYTE [r12], dl
+            ),
+            CellWidth::U16 => dynasm!(ops
+                ; mov WORD [r12], dx
+            ),
+            CellWidth::U32 => dynasm!(ops
+                ; mov DWORD [r12], edx
+            ),
+        }
+    }
+}
+
+impl Runtime for AotRuntime {
+    fn prologue<D: Asm>(&self, ops: &mut D) {
+        // output_buffer: rbx
+        // memory_start:  r13
+        // memory_end:    r14
+        // ptr:           rcx
+
+        dynasm!(ops
+            ; lea rbx, [->image_end]
+            ; add rbx, 15
+            ; and rbx, -16                  // output_buffer
+            ; lea r13, [rbx + std::mem::size_of::<OutputBuffer>() as i32]
+            ; mov rax, QWORD self.tape_bytes as i64
+            ; lea r14, [r13 + rax]
+            ; mov rax, QWORD self.origin as i64
+            ; lea rcx, [r13 + rax]
+        );
+    }
+
+    fn get_byte<D: Asm>(&self, ops: &mut D) {
+        dynasm!(ops
+            ; mov r12, rsi              // save the cell
+            ; push 0
+            ; read:
+            ; xor edi, edi              // stdin
+            ; mov rsi, rsp
+            ; mov edx, 1
+            ; mov eax, SYS_READ
+            ; syscall
+            ; cmp rax, -EINTR
+            ; je  <read
+            ; pop rdx                   // the byte read, if any
+            ; test rax, rax
+            ; js  ->io_error
+            ; jz  >eof
+        );
+        self.store_edx(ops);
+        dynasm!(ops
+            ; jmp >done
+            ; eof:
+        );
+        match self.eof {
+            EofPolicy::Unchanged => {}
+            EofPolicy::Zero => {
+                dynasm!(ops
+                    ; xor edx, edx
+                );
+                self.store_edx(ops);
+            }
+            EofPolicy::MinusOne => {
+                dynasm!(ops
+                    ; mov edx, -1
+                );
+                self.store_edx(ops);
+            }
+        }
+        dynasm!(ops
+            ; done:
+        );
+    }
+
+    fn flush<D: Asm>(&self, ops: &mut D) {
+        dynasm!(ops
+            ; lea rsi, [rbx + OUTPUT_DATA]
+            ; mov rdx, QWORD [rbx]      // len
+            ; write:
+            ; test rdx, rdx
+            ; jz  >done
+            ; mov edi, 1                // stdout
+            ; mov eax, SYS_WRITE
+            ; syscall
+            ; cmp rax, -EINTR
+            ; je  <write
+            ; test rax, rax
+            ; jle ->io_error
+            ; add rsi, rax
+            ; sub rdx, rax
+            ; jmp <write
+            ; done:
+            ; mov QWORD [rbx], 0        // len = 0
+        );
+    }
+
+    fn epilogue<D: Asm>(&self, ops: &mut D) {
+        self.flush(ops);
+        dynasm!(ops
+            ; xor edi, edi
+            ; mov eax, SYS_EXIT
+            ; syscall
+            ; -> overflow:
+        );
+        // stdout and stderr may be the same terminal, so the output so far
+        // goes ahead of the message
+        self.flush(ops);
+        dynasm!(ops
+            ; mov edi, 2                // stderr
+            ; lea rsi, [->overflow_message]
+            ; mov edx, OVERFLOW_MESSAGE.len() as i32
+            ; mov eax, SYS_WRITE
+            ; syscall
+            ; -> io_error:
+            ; mov edi, 1
+            ; mov eax, SYS_EXIT
+            ; syscall
+            ; -> overflow_message:
+            ; .bytes OVERFLOW_MESSAGE.iter().copied()
+        );
+    }
+}
+
+#[test]
+fn test_build() {
+    use crate::bfio::{run_exe, scratch_dir};
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = scratch_dir();
+    let dir = tmp.path();
+
+    let run = |name: &str, src: &str, config: VmConfig, input: &[u8]| {
+        let mut ir = crate::bfir::compile(src).unwrap();
+        crate::bfir::optimize(&mut ir);
+        let path = dir.join(name);
+        std::fs::write(&path, build(&ir, &config).unwrap()).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        run_exe(&path, input)
+    };
+
+    // the alphabet is printed from data in the image, then the input echoed;
+    // it is longer than the 15 bytes the buffer's alignment may leave after it
+    let src = "++++++++[>++++++++<-]>+".to_string() + &".+".repeat(26) + ",.";
+    let out = run("hello", &src, VmConfig::new(), b"!");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"ABCDEFGHIJKLMNOPQRSTUVWXYZ!");
+
+    let input: Vec<u8> = (0..10000).map(|i| (i % 251) as u8 + 1).collect();
+    let out = run("cat", ",[.,]", VmConfig::new().eof(EofPolicy::Zero), &input);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, input);
+
+    let config = VmConfig::new()
+        .cell_width(CellWidth::U16)
+        .eof(EofPolicy::MinusOne);
+    let out = run("eof", ",+[-[-]+.,+]", config, b"ab");
+    assert_eq!(out.stdout, b"\x01\x01");
+
+    let out = run("overflow", "+.<", VmConfig::new().tape_size(4), b"");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(out.stdout, b"\x01");
+    assert_eq!(out.stderr, OVERFLOW_MESSAGE);
+}
@@ -0,0 +1,375 @@
+use crate::bfinterp::Interpreter;
+use crate::bfir::{self, BfIR, Position, Token};
+use crate::bfjit::BfVM;
+use crate::config::VmConfig;
+use crate::error::Result;
+
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// Why [`Debugger::resume`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// About to run the op at a breakpoint.
+    Breakpoint(Position),
+    /// The program ran to its end.
+    Finished,
+}
+
+/// Runs a program one op at a time on the interpreter, without optimizing
+/// it, so every op maps back to a single source character.
+pub struct Debugger<'io> {
+    vm: BfVM<'io>,
+    interp: Rc<Interpreter>,
+    positions: Vec<Position>,
+    breakpoints: BTreeSet<usize>,
+    pc: usize,
+    ptr: usize,
+}
+
+impl<'io> Debugger<'io> {
+    /// Compiles `src` and stops before its first op. Every `#` in `src` is a
+    /// breakpoint.
+    pub fn new(
+        src: &str,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        config: VmConfig,
+    ) -> Result<Self> {
+        let (code, positions) = bfir::compile_with_positions(src)?;
+        let interp = Rc::new(Interpreter::new(code));
+        let ptr = config.tape_origin;
+        let vm = BfVM::with_interpreter(interp.clone(), positions.clone(), input, output, config)?;
+
+        let mut this = Self {
+            vm,
+            interp,
+            positions,
+            breakpoints: BTreeSet::new(),
+            pc: 0,
+            ptr,
+        };
+
+        let mut hash = false;
+        for token in bfir::tokenize(src) {
+            match token {
+                Token::Comment(text) => hash |= text.contains('#'),
+                Token::Command(_, pos) if std::mem::take(&mut hash) => {
+                    this.add_breakpoint(pos);
+                }
+                Token::Command(..) => {}
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Sets a breakpoint on the first op at or after `pos` and returns where
+    /// it ended up, or `None` if no op follows `pos`.
+    pub fn add_breakpoint(&mut self, pos: Position) -> Option<Position> {
+        let pc = self.positions.partition_point(|&p| p < pos);
+        let at = *self.positions.get(pc)?;
+        self.breakpoints.insert(pc);
+        Some(at)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = Position> + '_ {
+        self.breakpoints.iter().map(|&pc| self.positions[pc])
+    }
+
+    /// Position of the op that runs next, or `None` at the end.
+    pub fn position(&self) -> Option<Position> {
+        self.positions.get(self.pc).copied()
+    }
+
+    /// The op that runs next, or `None` at the end.
+    pub fn current_op(&self) -> Option<BfIR> {
+        self.interp.code().get(self.pc).copied()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc == self.interp.code().len()
+    }
+
+    /// The cell the pointer is on.
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Values of the cells from `start`, stopping at the end of the tape.
+    pub fn tape(&self, start: usize, len: usize) -> Vec<u32> {
+        let end = start.saturating_add(len).min(self.vm.config.tape_size);
+        (start.min(end)..end).map(|p| self.vm.load(p)).collect()
+    }
+
+    /// Runs one op. Does nothing once the program has finished.
+    pub fn step(&mut self) -> Result<()> {
+        if !self.is_finished() {
+            let ret = self.interp.step(&mut self.vm, &mut self.pc, &mut self.ptr);
+            self.vm.flush_output()?;
+            ret?;
+        }
+        Ok(())
+    }
+
+    /// Runs at least one op, then on until a breakpoint or the end.
+    pub fn resume(&mut self) -> Result<Stop> {
+        let ret = self.run_to_breakpoint();
+        self.vm.flush_output()?;
+        ret
+    }
+
+    fn run_to_breakpoint(&mut self) -> Result<Stop> {
+        let len = self.interp.code().len();
+        while self.pc < len {
+            self.interp
+                .step(&mut self.vm, &mut self.pc, &mut self.ptr)?;
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(Stop::Breakpoint(self.positions[self.pc]));
+            }
+        }
+        Ok(Stop::Finished)
+    }
+}
+
+const HELP: &str = "\
+commands:
+  s, step [N]           run N ops (default 1)
+  c, continue           run until a breakpoint or the end
+  b, break [LINE:COL]   set a breakpoint, or list them
+  p, ptr                print the pointer and its cell
+  x, tape [START [LEN]] dump LEN cells from START (default: around the pointer)
+  h, help               print this message
+  q, quit               stop debugging
+";
+
+/// Reads debugger commands a line at a time with `read_line`, until the
+/// input ends or `quit`, and writes the replies to `out`. Nothing is held
+/// between lines, so the commands may come from the program's own input.
+pub fn repl(
+    dbg: &mut Debugger<'_>,
+    mut read_line: impl FnMut(&mut String) -> std::io::Result<usize>,
+    mut out: impl Write,
+) -> Result<()> {
+    print_location(dbg, &mut out)?;
+    loop {
+        write!(out, "(bfdb) ")?;
+        out.flush()?;
+
+        let mut line = String::new();
+        if read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        let Some(cmd) = words.next() else { continue };
+        let args: Vec<&str> = words.collect();
+
+        let ret = match (cmd, args.as_slice()) {
+            ("s" | "step", n) if n.len() <= 1 => {
+                match n.first().map_or(Ok(1), |n| n.parse::<usize>()) {
+                    Ok(n) => (0..n)
+                        .map_while(|_| (!dbg.is_finished()).then(|| dbg.step()))
+                        .collect::<Result<()>>(),
+                    Err(_) => {
+                        writeln!(out, "expected a number of ops")?;
+                        continue;
+                    }
+                }
+                .map(|()| true)
+            }
+            ("c" | "continue", []) => dbg.resume().map(|_| true),
+            ("b" | "break", []) => {
+                for pos in dbg.breakpoints() {
+                    writeln!(out, "breakpoint at {}", pos)?;
+                }
+                Ok(false)
+            }
+            ("b" | "break", [pos]) => {
+                match parse_position(pos).map(|pos| dbg.add_breakpoint(pos)) {
+                    Some(Some(at)) => writeln!(out, "breakpoint at {}", at)?,
+                    Some(None) => writeln!(out, "no op at or after {}", pos)?,
+                    None => writeln!(out, "expected LINE:COL")?,
+                }
+                Ok(false)
+            }
+            ("p" | "ptr", []) => {
+                let ptr = dbg.ptr();
+                writeln!(out, "ptr = {}, *ptr = {}", ptr, dbg.tape(ptr, 1)[0])?;
+                Ok(false)
+            }
+            ("x" | "tape", args) if args.len() <= 2 => {
+                let nums: std::result::Result<Vec<usize>, _> =
+                    args.iter().map(|a| a.parse::<usize>()).collect();
+                let (start, len) = match nums.as_deref() {
+                    Ok([]) => (dbg.ptr().saturating_sub(8), 16),
+                    Ok([start]) => (*start, 16),
+                    Ok([start, len]) => (*start, *len),
+                    _ => {
+                        writeln!(out, "expected START and LEN as numbers")?;
+                        continue;
+                    }
+                };
+                print_tape(dbg, start, len, &mut out)?;
+                Ok(false)
+            }
+            ("h" | "help", []) => {
+                write!(out, "{}", HELP)?;
+                Ok(false)
+            }
+            ("q" | "quit", []) => return Ok(()),
+            _ => {
+                writeln!(out, "unknown command `{}`, try `help`", line.trim())?;
+                Ok(false)
+            }
+        };
+
+        match ret {
+            Ok(true) => print_location(dbg, &mut out)?,
+            Ok(false) => {}
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+    }
+}
+
+fn parse_position(s: &str) -> Option<Position> {
+    let (line, col) = s.split_once(':')?;
+    Some(Position {
+        line: line.parse().ok()?,
+        col: col.parse().ok()?,
+    })
+}
+
+fn print_location(dbg: &Debugger<'_>, out: &mut impl Write) -> Result<()> {
+    match (dbg.position(), dbg.current_op()) {
+        (Some(pos), Some(op)) => writeln!(out, "at {}: `{}`", pos, op_char(op))?,
+        _ => writeln!(out, "program finished")?,
+    }
+    Ok(())
+}
+
+/// Prints eight cells per row, with the pointer's cell in brackets.
+fn print_tape(dbg: &Debugger<'_>, start: usize, len: usize, out: &mut impl Write) -> Result<()> {
+    for (row, cells) in dbg.tape(start, len).chunks(8).enumerate() {
+        let first = start + row * 8;
+        write!(out, "{:>8}:", first)?;
+        for (i, cell) in cells.iter().enumerate() {
+            match first + i == dbg.ptr() {
+                true => write!(out, " [{:>3}]", cell)?,
+                false => write!(out, "  {:>3} ", cell)?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// The source character an unoptimized op was compiled from.
+fn op_char(op: BfIR) -> char {
+    use BfIR::*;
+    match op {
+        AddVal { .. } => '+',
+        SubVal { .. } => '-',
+        AddPtr(_) => '>',
+        SubPtr(_) => '<',
+        GetByte { .. } => ',',
+        PutByte { .. } => '.',
+        Jz => '[',
+        Jnz => ']',
+        _ => '?',
+    }
+}
+
+#[test]
+fn test_debugger() {
+    use crate::bfio::{input_from, output_to};
+    use std::cell::RefCell;
+    use std::io::BufRead;
+
+    let src = "++>+++#\n[-<+>]\n<.";
+    let mut output = vec![];
+    let mut dbg =
+        Debugger::new(src, input_from(""), output_to(&mut output), VmConfig::new()).unwrap();
+
+    assert_eq!(dbg.position(), Some(Position { line: 1, col: 1 }));
+    dbg.step().unwrap();
+    dbg.step().unwrap();
+    dbg.step().unwrap();
+    assert_eq!(dbg.ptr(), 1);
+    assert_eq!(dbg.tape(0, 2), vec![2, 0]);
+
+    // the `#` stops before the loop
+    assert_eq!(
+        dbg.resume().unwrap(),
+        Stop::Breakpoint(Position { line: 2, col: 1 })
+    );
+    assert_eq!(dbg.tape(0, 2), vec![2, 3]);
+
+    assert_eq!(
+        dbg.add_breakpoint(Position { line: 2, col: 7 }),
+        Some(Position { line: 3, col: 1 })
+    );
+    assert_eq!(
+        dbg.resume().unwrap(),
+        Stop::Breakpoint(Position { line: 3, col: 1 })
+    );
+    assert_eq!(dbg.tape(0, 2), vec![5, 0]);
+    assert_eq!(dbg.resume().unwrap(), Stop::Finished);
+    assert!(dbg.is_finished());
+    drop(dbg);
+    assert_eq!(output, [5]);
+
+    // breakpoints sit where the compiler puts the ops, whatever the line ends
+    let dbg = Debugger::new(
+        "+\r\n #+",
+        input_from(""),
+        Box::new(vec![]),
+        VmConfig::new(),
+    )
+    .unwrap();
+    assert_eq!(
+        dbg.breakpoints().collect::<Vec<_>>(),
+        [Position { line: 2, col: 3 }]
+    );
+    assert_eq!(dbg.position(), Some(Position { line: 1, col: 1 }));
+
+    let mut dbg = Debugger::new("+>++", input_from(""), Box::new(vec![]), VmConfig::new()).unwrap();
+    let mut out = vec![];
+    let mut commands = &b"step 2\nptr\nx 0 3\nbreak 1:4\nc\nbogus\nstep 18446744073709551615\n"[..];
+    repl(&mut dbg, |line| commands.read_line(line), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("at 1:3: `+`"), "{}", out);
+    assert!(out.contains("ptr = 1, *ptr = 0"), "{}", out);
+    assert!(out.contains("       0:    1  [  0]    0"), "{}", out);
+    assert!(out.contains("at 1:4: `+`"), "{}", out);
+    assert!(out.contains("unknown command `bogus`"), "{}", out);
+
+    // commands and the program's input share one stream, as they do on stdin
+    struct Shared<'a>(Rc<RefCell<&'a [u8]>>);
+    impl Read for Shared<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().read(buf)
+        }
+    }
+    let stdin = Rc::new(RefCell::new(&b"s\nxs\np\nq\n"[..]));
+    let mut output = vec![];
+    let mut dbg = Debugger::new(
+        ",.",
+        Box::new(Shared(stdin.clone())),
+        output_to(&mut output),
+        VmConfig::new(),
+    )
+    .unwrap();
+    let mut out = vec![];
+    repl(
+        &mut dbg,
+        |line| stdin.borrow_mut().read_line(line),
+        &mut out,
+    )
+    .unwrap();
+    drop(dbg);
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("ptr = 0, *ptr = 120"), "{}", out);
+    assert!(out.contains("program finished"), "{}", out);
+    assert_eq!(output, b"x");
+}
@@ -0,0 +1,262 @@
+use crate::bfir::{self, BfIR};
+use crate::bfjit::BfVM;
+use crate::config::CellWidth;
+use crate::config::VmConfig;
+use crate::error::{Result, RuntimeError};
+use crate::peval::{self, Prefix};
+
+/// Moves `ptr` by `offset` cells, failing if it leaves a tape of `len` cells.
+fn offset_ptr(ptr: usize, offset: i64, len: usize) -> std::result::Result<usize, RuntimeError> {
+    ptr.checked_add_signed(offset as isize)
+        .filter(|&p| p < len)
+        .ok_or(RuntimeError::PointerOverflow {
+            ptr: ptr as i64 + offset,
+            pos: None,
+        })
+}
+
+impl<'io> BfVM<'io> {
+    pub(crate) fn load(&self, p: usize) -> u32 {
+        let m = &self.memory;
+        match self.config.cell_width {
+            CellWidth::U8 => m[p] as u32,
+            CellWidth::U16 => u16::from_le_bytes([m[2 * p], m[2 * p + 1]]) as u32,
+            CellWidth::U32 => u32::from_le_bytes(m[4 * p..4 * p + 4].try_into().unwrap()),
+        }
+    }
+
+    fn store(&mut self, p: usize, val: u32) {
+        let n = self.config.cell_width.bytes();
+        self.memory[n * p..n * (p + 1)].copy_from_slice(&val.to_le_bytes()[..n]);
+    }
+}
+
+pub struct Interpreter {
+    code: Vec<BfIR>,
+    jumps: Vec<usize>,
+    prefix: Option<Prefix>,
+}
+
+impl Interpreter {
+    pub fn new(code: Vec<BfIR>) -> Self {
+        let jumps = bfir::jump_table(&code);
+
+        Self {
+            code,
+            jumps,
+            prefix: None,
+        }
+    }
+
+    /// Like [`new`](Self::new), but runs the part of `code` that reads no
+    /// input once, up front, for a VM with `config` to skip on its first run.
+    pub fn with_prefix(code: Vec<BfIR>, config: &VmConfig) -> Self {
+        let prefix = peval::eval_prefix(&code, config);
+        Self {
+            prefix,
+            ..Self::new(code)
+        }
+    }
+
+    pub fn run(&self, vm: &mut BfVM<'_>) -> Result<()> {
+        let mut pc = vm.pc;
+        let mut ptr = vm.ptr;
+
+        if let Some(prefix) = self.prefix.as_ref().filter(|_| pc == 0 && vm.fresh) {
+            for &(cell, val) in &prefix.cells {
+                vm.store(cell, val);
+            }
+            for &b in &prefix.output {
+                vm.write_byte(b)?;
+            }
+            (pc, ptr) = (prefix.pc, prefix.ptr);
+        }
+
+        while pc < self.code.len() {
+            if let Err(e) = self.step(vm, &mut pc, &mut ptr) {
+                // resume here after a pause
+                (vm.pc, vm.ptr) = (pc, ptr);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn code(&self) -> &[BfIR] {
+        &self.code
+    }
+
+    /// Executes the op at `pc`, then moves `pc` on to the next op to run.
+    #[inline(always)]
+    pub(crate) fn step(&self, vm: &mut BfVM<'_>, pc: &mut usize, ptr: &mut usize) -> Result<()> {
+        let op = *pc;
+        self.exec(vm, pc, ptr).map_err(|e| vm.locate(e, op).into())
+    }
+
+    #[inline(always)]
+    fn exec(
+        &self,
+        vm: &mut BfVM<'_>,
+        pc: &mut usize,
+        ptr: &mut usize,
+    ) -> std::result::Result<(), RuntimeError> {
+        let len = vm.config.tape_size;
+        let narrow = vm.config.cell_width == CellWidth::U8;
+
+        use BfIR::*;
+        match self.code[*pc] {
+            AddPtr(x) => *ptr = offset_ptr(*ptr, x as i64, len)?,
+            SubPtr(x) => *ptr = offset_ptr(*ptr, -(x as i64), len)?,
+            AddVal { offset, val } => {
+                let p = offset_ptr(*ptr, offset as i64, len)?;
+                vm.store(p, vm.load(p).wrapping_add(val));
+            }
+            SubVal { offset, val } => {
+                let p = offset_ptr(*ptr, offset as i64, len)?;
+                vm.store(p, vm.load(p).wrapping_sub(val));
+            }
+            GetByte { offset } => {
+                let p = offset_ptr(*ptr, offset as i64, len)?;
+                vm.flush_output()?;
+                if let Some(val) = vm.read_cell()? {
+                    vm.store(p, val);
+                }
+            }
+            PutByte { offset } => {
+                let p = offset_ptr(*ptr, offset as i64, len)?;
+                vm.write_byte(vm.load(p) as u8)?;
+            }
+            SetZero => vm.store(*ptr, 0),
+            SetValue(val) => vm.store(*ptr, val),
+            MulAdd { offset, factor } => {
+                let val = vm.load(*ptr);
+                if val != 0 {
+                    let target = offset_ptr(*ptr, offset as i64, len)?;
+                    vm.store(
+                        target,
+                        vm.load(target).wrapping_add(val.wrapping_mul(factor)),
+                    );
+                }
+            }
+            ScanRight(1) if narrow => {
+                *ptr += vm.memory[*ptr..].iter().position(|&b| b == 0).ok_or(
+                    RuntimeError::PointerOverflow {
+                        ptr: len as i64,
+                        pos: None,
+                    },
+                )?
+            }
+            ScanLeft(1) if narrow => {
+                *ptr = vm.memory[..=*ptr]
+                    .iter()
+                    .rposition(|&b| b == 0)
+                    .ok_or(RuntimeError::PointerOverflow { ptr: -1, pos: None })?
+            }
+            ScanRight(x) => {
+                while vm.load(*ptr) != 0 {
+                    *ptr = offset_ptr(*ptr, x as i64, len)?;
+                }
+            }
+            ScanLeft(x) => {
+                while vm.load(*ptr) != 0 {
+                    *ptr = offset_ptr(*ptr, -(x as i64), len)?;
+                }
+            }
+            Jz => {
+                if vm.load(*ptr) == 0 {
+                    *pc = self.jumps[*pc];
+                }
+            }
+            Jnz => {
+                vm.back_edge()?;
+                if vm.load(*ptr) != 0 {
+                    *pc = self.jumps[*pc];
+                }
+            }
+        }
+        *pc += 1;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_interp() {
+    use crate::bfio::{input_from, output_to};
+    use crate::bfjit::Backend;
+    use crate::config::VmConfig;
+
+    let src = "++++++++[>++++++++<-]>+.+.+.>,.<<<";
+
+    for backend in [Backend::Interp, Backend::Jit] {
+        let mut output = vec![];
+        let ret = BfVM::from_source(
+            src,
+            input_from("!"),
+            output_to(&mut output),
+            false,
+            VmConfig::new().backend(backend),
+        )
+        .and_then(|mut vm| vm.run());
+
+        match ret.unwrap_err() {
+            crate::error::VMError::Runtime(RuntimeError::PointerOverflow { .. }) => {}
+            e => panic!("{}", e),
+        }
+        assert_eq!(output, b"ABC!");
+    }
+}
+
+#[test]
+fn test_scan() {
+    use crate::bfio::{input_from, output_to};
+    use crate::bfjit::Backend;
+    use crate::config::VmConfig;
+
+    let mut src = String::from(">");
+    src += &"+>".repeat(40);
+    src += &"<".repeat(40);
+    src += "[>]";
+    src += &"+".repeat(65);
+    src += ".[-]<[<]";
+    src += &"+".repeat(66);
+    src += ".[-]>[>>>]";
+    src += &"+".repeat(67);
+    src += ".";
+
+    for backend in [Backend::Interp, Backend::Jit] {
+        let mut output = vec![];
+        BfVM::from_source(
+            &src,
+            input_from(""),
+            output_to(&mut output),
+            true,
+            VmConfig::new().backend(backend),
+        )
+        .and_then(|mut vm| vm.run())
+        .unwrap();
+        assert_eq!(output, b"ABC");
+    }
+
+    for src in [
+        "+>+[<]",
+        "+>+>+[<<]",
+        "+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+>+[<]",
+    ] {
+        for backend in [Backend::Interp, Backend::Jit] {
+            let ret = BfVM::from_source(
+                src,
+                input_from(""),
+                Box::new(vec![]),
+                true,
+                VmConfig::new().backend(backend),
+            )
+            .and_then(|mut vm| vm.run());
+            match ret.unwrap_err() {
+                crate::error::VMError::Runtime(RuntimeError::PointerOverflow { .. }) => {}
+                e => panic!("{}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::bfjit::BfVM;
+use crate::config::VmConfig;
+use crate::error::Result;
+
+use std::io::{Cursor, Read, Write};
+
+/// Input that reads `bytes`, then reports end of input. Takes a `&[u8]`, a
+/// `String`, a `Vec<u8>` and the like.
+pub fn input_from<'io>(bytes: impl AsRef<[u8]> + 'io) -> Box<dyn Read + 'io> {
+    Box::new(Cursor::new(bytes))
+}
+
+/// Input that calls `next` for each byte. `next` returns `None` at end of
+/// input.
+pub fn input_fn<'io>(next: impl FnMut() -> Option<u8> + 'io) -> Box<dyn Read + 'io> {
+    Box::new(FnInput(next))
+}
+
+/// Output that appends to `buf`.
+pub fn output_to(buf: &mut Vec<u8>) -> Box<dyn Write + '_> {
+    Box::new(buf)
+}
+
+/// Output that calls `put` with each byte.
+pub fn output_fn<'io>(put: impl FnMut(u8) + 'io) -> Box<dyn Write + 'io> {
+    Box::new(FnOutput(put))
+}
+
+struct FnInput<F>(F);
+
+impl<F: FnMut() -> Option<u8>> Read for FnInput<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(slot) = buf.first_mut() else {
+            return Ok(0);
+        };
+        match (self.0)() {
+            Some(b) => {
+                *slot = b;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+struct FnOutput<F>(F);
+
+impl<F: FnMut(u8)> Write for FnOutput<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        buf.iter().for_each(|&b| (self.0)(b));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `src`, optimized and with the default config, on `input` and
+/// returns what it printed. Bytes that are not UTF-8 become U+FFFD.
+pub fn run_to_string(src: &str, input: impl AsRef<[u8]>) -> Result<String> {
+    let mut output = vec![];
+    BfVM::from_source(
+        src,
+        input_from(input),
+        output_to(&mut output),
+        true,
+        VmConfig::new(),
+    )?
+    .run()?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// A directory for the files a test writes, removed when dropped, even if
+/// an assertion fails.
+#[cfg(test)]
+pub(crate) fn scratch_dir() -> tempfile::TempDir {
+    tempfile::tempdir().unwrap()
+}
+
+/// Runs the executable at `path` with `input` piped to it, and collects what
+/// it printed and how it exited.
+#[cfg(test)]
+pub(crate) fn run_exe(path: &std::path::Path, input: &[u8]) -> std::process::Output {
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_io() {
+    let src = ",[.[-],]";
+    assert_eq!(run_to_string(src, "héllo").unwrap(), "héllo");
+    assert_eq!(run_to_string(src, String::from("abc")).unwrap(), "abc");
+    assert_eq!(run_to_string(src, [0xff, b'!']).unwrap(), "\u{fffd}!");
+
+    let mut input = b"xyz".iter().copied();
+    let mut printed = vec![];
+    BfVM::from_source(
+        src,
+        input_fn(|| input.next()),
+        output_fn(|b| printed.push(b.to_ascii_uppercase())),
+        false,
+        VmConfig::new(),
+    )
+    .and_then(|mut vm| vm.run())
+    .unwrap();
+    assert_eq!(printed, b"XYZ");
+}
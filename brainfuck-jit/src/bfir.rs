@@ -1,113 +1,398 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::{fmt::Display, vec};
+
+use unicode_width::UnicodeWidthChar;
+
 impl std::error::Error for CompileError {}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BfIR {
-    AddVal(u8),  // +
-    SubVal(u8),  // -
-    AddPtr(u32), // >
-    SubPtr(u32), // <
-    GetByte,     // ,
-    PutByte,     // .
-    Jz,          // [
-    Jnz,         // ]
+    AddVal { offset: i32, val: u32 }, // +
+    SubVal { offset: i32, val: u32 }, // -
+    AddPtr(u32),                      // >
+    SubPtr(u32),                      // <
+    GetByte { offset: i32 },          // ,
+    PutByte { offset: i32 },          // .
+    Jz,                               // [
+    Jnz,                              // ]
+
+    SetZero,                             // [-]
+    SetValue(u32),                       // [-]+++
+    MulAdd { offset: i32, factor: u32 }, // *(ptr + offset) += *ptr * factor
+    ScanRight(u32),                      // [>]
+    ScanLeft(u32),                       // [<]
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
 pub enum CompileErrorKind {
-    #[error("Unclose left bracket")]
+    #[error("unclosed left bracket")]
     UnclosedLeftBracket,
-    #[error("Unexpected right bracket")]
+    #[error("unexpected right bracket")]
     UnexpectedRightBracket,
 }
 
+/// One unbalanced bracket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BracketError {
+    pub kind: CompileErrorKind,
+    pub pos: Position,
+    /// Just past the last character of the source, for an unclosed `[`.
+    pub end: Option<Position>,
+}
+
+/// Every unbalanced bracket in a source, in order. Displays them the way
+/// rustc does, under the source lines they point at.
 #[derive(Debug)]
 pub struct CompileError {
-    line: u32,
-    col: u32,
-    kind: CompileErrorKind,
+    errors: Vec<BracketError>,
+    /// The source lines the errors point at, by line number.
+    lines: BTreeMap<u32, String>,
+    /// The file the source came from, if known.
+    path: Option<PathBuf>,
+}
+
+impl CompileError {
+    pub fn errors(&self) -> &[BracketError] {
+        &self.errors
+    }
+
+    /// Names the file the source came from in the positions it displays.
+    pub fn with_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
 }
 
 impl Display for CompileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} at line {}:{}", self.kind, self.line, self.col)
+        let width = self.lines.keys().last().map_or(1, |n| n.to_string().len());
+        let gutter = " ".repeat(width);
+        for e in &self.errors {
+            let mut labels = vec![(e.pos, "^", "")];
+            match e.kind {
+                CompileErrorKind::UnclosedLeftBracket => {
+                    labels[0].2 = "this `[` is never closed";
+                    labels.extend(e.end.map(|end| (end, "-", "the program ends here")));
+                }
+                CompileErrorKind::UnexpectedRightBracket => labels[0].2 = "no `[` to close",
+            }
+
+            writeln!(f, "error: {}", e.kind)?;
+            match &self.path {
+                Some(path) => writeln!(f, "{}--> {}:{}", gutter, path.display(), e.pos)?,
+                None => writeln!(f, "{}--> {}", gutter, e.pos)?,
+            }
+            writeln!(f, "{} |", gutter)?;
+            let mut shown = None;
+            for (pos, mark, label) in labels {
+                let line = &self.lines[&pos.line];
+                if shown != Some(pos.line) {
+                    if shown.is_some_and(|n| n + 1 < pos.line) {
+                        writeln!(f, "...")?;
+                    }
+                    writeln!(f, "{:>width$} | {}", pos.line, line)?;
+                    shown = Some(pos.line);
+                }
+                // keep tabs, and pad wide characters, so the mark lines up
+                let pad: String = line
+                    .chars()
+                    .take(pos.col as usize - 1)
+                    .map(|c| match c {
+                        '\t' => "\t".to_string(),
+                        c => " ".repeat(c.width().unwrap_or(0)),
+                    })
+                    .collect();
+                writeln!(f, "{} | {}{} {}", gutter, pad, mark, label)?;
+            }
+            writeln!(f)?;
+        }
+        match self.errors.len() {
+            1 => write!(f, "error: aborting due to 1 previous error"),
+            n => write!(f, "error: aborting due to {} previous errors", n),
+        }
     }
 }
 
-pub fn compile(src: &str) -> Result<Vec<BfIR>, CompileError> {
-    let mut code: Vec<BfIR> = vec![];
+/// A line and a column, both counted from 1 in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Position {
+    pub line: u32,
+    pub col: u32,
+}
 
-    let mut stk: Vec<(u32, u32, u32)> = vec![];
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Shows an op as the C-like statement it stands for, on the cells `p[..]`
+/// around the pointer `p`.
+impl Display for BfIR {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use BfIR::*;
+        match *self {
+            AddVal { offset, val } => write!(f, "p[{}] += {}", offset, val),
+            SubVal { offset, val } => write!(f, "p[{}] -= {}", offset, val),
+            AddPtr(x) => write!(f, "p += {}", x),
+            SubPtr(x) => write!(f, "p -= {}", x),
+            GetByte { offset } => write!(f, "p[{}] = getchar()", offset),
+            PutByte { offset } => write!(f, "putchar(p[{}])", offset),
+            Jz => write!(f, "while p[0] {{"),
+            Jnz => write!(f, "}}"),
+            SetZero => write!(f, "p[0] = 0"),
+            SetValue(val) => write!(f, "p[0] = {}", val),
+            MulAdd { offset, factor } => write!(f, "p[{}] += p[0] * {}", offset, factor),
+            ScanRight(x) => write!(f, "while p[0] {{ p += {} }}", x),
+            ScanLeft(x) => write!(f, "while p[0] {{ p -= {} }}", x),
+        }
+    }
+}
+
+/// A piece of Brainfuck source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// One of `+-<>,.[]`.
+    Command(char, Position),
+    /// All the text between two commands.
+    Comment(&'a str),
+}
+
+/// Splits `src` into commands and the comments between them.
+pub fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut comment = 0;
 
     let mut line: u32 = 1;
     let mut col: u32 = 0;
 
-    for ch in src.chars() {
+    for (i, ch) in src.char_indices() {
         col += 1;
         match ch {
             '\n' => {
                 line += 1;
                 col = 0;
             }
-            '+' => code.push(BfIR::AddVal(1)),
-            '-' => code.push(BfIR::SubVal(1)),
+            '+' | '-' | '>' | '<' | ',' | '.' | '[' | ']' => {
+                if comment < i {
+                    tokens.push(Token::Comment(&src[comment..i]));
+                }
+                tokens.push(Token::Command(ch, Position { line, col }));
+                comment = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if comment < src.len() {
+        tokens.push(Token::Comment(&src[comment..]));
+    }
+    tokens
+}
+
+pub fn compile(src: &str) -> Result<Vec<BfIR>, CompileError> {
+    compile_with_positions(src).map(|(code, _)| code)
+}
+
+/// Like [`compile`], but also returns the source position of each op.
+pub fn compile_with_positions(src: &str) -> Result<(Vec<BfIR>, Vec<Position>), CompileError> {
+    let mut code: Vec<BfIR> = vec![];
+    let mut positions: Vec<Position> = vec![];
+    let mut errors = vec![];
+
+    let mut stk: Vec<Position> = vec![];
+
+    for token in tokenize(src) {
+        let Token::Command(ch, pos) = token else {
+            continue;
+        };
+        match ch {
+            '+' => code.push(BfIR::AddVal { offset: 0, val: 1 }),
+            '-' => code.push(BfIR::SubVal { offset: 0, val: 1 }),
             '>' => code.push(BfIR::AddPtr(1)),
             '<' => code.push(BfIR::SubPtr(1)),
-            ',' => code.push(BfIR::GetByte),
-            '.' => code.push(BfIR::PutByte),
+            ',' => code.push(BfIR::GetByte { offset: 0 }),
+            '.' => code.push(BfIR::PutByte { offset: 0 }),
             '[' => {
-                let pos = code.len() as u32;
-                stk.push((pos, line, col));
+                stk.push(pos);
                 code.push(BfIR::Jz)
             }
-            ']' => {
-                stk.pop().ok_or(CompileError {
-                    line,
-                    col,
+            ']' => match stk.pop() {
+                Some(_) => code.push(BfIR::Jnz),
+                // skip it, and look for more
+                None => errors.push(BracketError {
                     kind: CompileErrorKind::UnexpectedRightBracket,
-                })?;
-                code.push(BfIR::Jnz)
-            }
-            _ => {}
+                    pos,
+                    end: None,
+                }),
+            },
+            _ => unreachable!(),
         }
+        positions.resize(code.len(), pos);
     }
 
-    if let Some((_, line, col)) = stk.pop() {
+    let last = src.rsplit('\n').next().unwrap();
+    let end = Position {
+        line: src.matches('\n').count() as u32 + 1,
+        col: last.chars().count() as u32 + 1,
+    };
+    errors.extend(stk.into_iter().map(|pos| BracketError {
+        kind: CompileErrorKind::UnclosedLeftBracket,
+        pos,
+        end: Some(end),
+    }));
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| e.pos);
+        let text: Vec<_> = src.split('\n').collect();
+        let lines = errors
+            .iter()
+            .flat_map(|e| [Some(e.pos), e.end])
+            .flatten()
+            .map(|pos| {
+                let line = text[pos.line as usize - 1].trim_end_matches('\r');
+                (pos.line, line.to_string())
+            })
+            .collect();
         return Err(CompileError {
-            line,
-            col,
-            kind: CompileErrorKind::UnclosedLeftBracket,
+            errors,
+            lines,
+            path: None,
         });
     }
 
-    Ok(code)
+    Ok((code, positions))
+}
+
+/// Returns, for each `Jz` and `Jnz` in `code`, the index of the bracket it
+/// matches. The loops in `code` must be balanced.
+pub(crate) fn jump_table(code: &[BfIR]) -> Vec<usize> {
+    let mut jumps = vec![0; code.len()];
+    let mut stk = vec![];
+    for (pc, &ir) in code.iter().enumerate() {
+        match ir {
+            BfIR::Jz => stk.push(pc),
+            BfIR::Jnz => {
+                let left = stk.pop().unwrap();
+                jumps[left] = pc;
+                jumps[pc] = left;
+            }
+            _ => {}
+        }
+    }
+    jumps
 }
 
 pub fn optimize(code: &mut Vec<BfIR>) {
+    let mut positions = vec![Position::default(); code.len()];
+    optimize_with_positions(code, &mut positions);
+}
+
+/// Like [`optimize`], but keeps `positions` in step with `code`. An op that
+/// replaces several others takes the position of the first of them.
+pub fn optimize_with_positions(code: &mut Vec<BfIR>, positions: &mut Vec<Position>) {
+    assert_eq!(code.len(), positions.len());
+    fold_runs(code, positions);
+    fold_loops(code, positions);
+    drop_dead_code(code, positions);
+    // dropping loops can leave runs that cancel out
+    fold_runs(code, positions);
+    sink_ptr_moves(code, positions);
+}
+
+/// Merges runs of pointer moves, and runs of arithmetic on the same cell,
+/// into single ops. `+` and `-` that cancel out leave nothing behind. A run
+/// of moves that turns back keeps a move to each far end it turns back
+/// from, as running off the tape there is still an error.
+fn fold_runs(code: &mut Vec<BfIR>, positions: &mut Vec<Position>) {
     let len = code.len();
     let mut i = 0;
     let mut pc = 0;
 
     macro_rules! _fold_ir {
-        ($variant:ident, $x: ident) => {{
-            let mut j = i + 1;
+        (AddPtr | SubPtr) => {{
+            // the lowest and highest the run goes, and where it first gets there
+            let (mut net, mut lo, mut hi): (i64, i64, i64) = (0, 0, 0);
+            let (mut lo_at, mut hi_at) = (i, i);
+            let mut j = i;
             while j < len {
-                if let $variant(d) = code[j] {
-                    $x = $x.wrapping_add(d);
-                } else {
-                    break;
+                match code[j] {
+                    AddPtr(x) => net += x as i64,
+                    SubPtr(x) => net -= x as i64,
+                    _ => break,
+                }
+                if net < lo {
+                    (lo, lo_at) = (net, j);
+                }
+                if net > hi {
+                    (hi, hi_at) = (net, j);
                 }
                 j += 1;
             }
+            let pos = positions[i];
             i = j;
-            code[pc] = $variant($x);
-            pc += 1
+
+            // a far end the run turns back from must still be bounds-checked
+            let mut stops = vec![];
+            if lo < net.min(0) {
+                stops.push((lo_at, lo));
+            }
+            if hi > net.max(0) {
+                stops.push((hi_at, hi));
+            }
+            stops.sort();
+            stops.push((j, net));
+
+            // a run of n ops never needs more than n ops to make its stops
+            let mut cur = 0;
+            for (_, stop) in stops {
+                let mut d = stop - cur;
+                cur = stop;
+                while d != 0 {
+                    let step = d.clamp(-(u32::MAX as i64), u32::MAX as i64);
+                    code[pc] = match step {
+                        x if x > 0 => AddPtr(x as u32),
+                        x => SubPtr(x.unsigned_abs() as u32),
+                    };
+                    positions[pc] = pos;
+                    pc += 1;
+                    d -= step;
+                }
+            }
+        }};
+        (AddVal | SubVal { $offset:ident }) => {{
+            let mut net: u32 = 0;
+            let mut j = i;
+            while j < len {
+                match code[j] {
+                    AddVal { offset, val } if offset == $offset => net = net.wrapping_add(val),
+                    SubVal { offset, val } if offset == $offset => net = net.wrapping_sub(val),
+                    _ => break,
+                }
+                j += 1;
+            }
+            let pos = positions[i];
+            i = j;
+            if net != 0 {
+                code[pc] = match net as i32 {
+                    x if x > 0 => AddVal {
+                        offset: $offset,
+                        val: net,
+                    },
+                    _ => SubVal {
+                        offset: $offset,
+                        val: net.wrapping_neg(),
+                    },
+                };
+                positions[pc] = pos;
+                pc += 1;
+            }
         }};
     }
 
     macro_rules! _normal_ir {
         () => {{
             code[pc] = code[i];
+            positions[pc] = positions[i];
             pc += 1;
             i += 1;
         }};
@@ -116,18 +401,426 @@ pub fn optimize(code: &mut Vec<BfIR>) {
     use BfIR::*;
     while i < len {
         match code[i] {
-            AddPtr(mut x) => _fold_ir!(AddPtr, x),
-            SubPtr(mut x) => _fold_ir!(SubPtr, x),
-            AddVal(mut x) => _fold_ir!(AddVal, x),
-            SubVal(mut x) => _fold_ir!(SubVal, x),
-            GetByte => _normal_ir!(),
-            PutByte => _normal_ir!(),
+            AddPtr(_) | SubPtr(_) => _fold_ir!(AddPtr | SubPtr),
+            AddVal { offset, .. } | SubVal { offset, .. } => _fold_ir!(AddVal | SubVal { offset }),
+            GetByte { .. } => _normal_ir!(),
+            PutByte { .. } => _normal_ir!(),
             Jz => _normal_ir!(),
             Jnz => _normal_ir!(),
+            SetZero | SetValue(_) | MulAdd { .. } => _normal_ir!(),
+            ScanRight(_) | ScanLeft(_) => _normal_ir!(),
         }
     }
     code.truncate(pc);
     code.shrink_to_fit();
+    positions.truncate(pc);
+    positions.shrink_to_fit();
+}
+
+/// Replaces balanced arithmetic loops such as `[-]` and `[->+>++<<]` with
+/// `MulAdd` ops followed by a `SetZero`, and pointer-only loops such as `[>]`
+/// with a scan.
+fn fold_loops(code: &mut Vec<BfIR>, positions: &mut Vec<Position>) {
+    let mut out = Vec::with_capacity(code.len());
+    let mut out_positions = Vec::with_capacity(code.len());
+    let mut i = 0;
+
+    while i < code.len() {
+        if code[i] == BfIR::Jz {
+            if let Some(ir) = scan_loop(&code[i..]) {
+                out.push(ir);
+                out_positions.push(positions[i]);
+                i += 3;
+                continue;
+            }
+            if let Some((len, ops)) = balanced_loop(&code[i..]) {
+                out_positions.resize(out.len() + ops.len(), positions[i]);
+                out.extend(ops);
+                i += len;
+                continue;
+            }
+        }
+        out.push(code[i]);
+        out_positions.push(positions[i]);
+        i += 1;
+    }
+
+    *code = out;
+    *positions = out_positions;
+}
+
+/// Matches `code` against a loop that only steps the pointer.
+fn scan_loop(code: &[BfIR]) -> Option<BfIR> {
+    use BfIR::*;
+    match code {
+        [Jz, AddPtr(x), Jnz, ..] => Some(ScanRight(*x)),
+        [Jz, SubPtr(x), Jnz, ..] => Some(ScanLeft(*x)),
+        _ => None,
+    }
+}
+
+/// Matches `code` against a loop that only does arithmetic, moves the pointer
+/// back to where it started and steps the current cell by one. Returns the
+/// length of the matched loop and its replacement.
+///
+/// The replacement only checks the cells it changes, so a loop that goes
+/// further than those is left alone.
+fn balanced_loop(code: &[BfIR]) -> Option<(usize, Vec<BfIR>)> {
+    let mut deltas: BTreeMap<i32, u32> = BTreeMap::new();
+    let mut offset: i32 = 0;
+    // the lowest and highest cells the loop goes to or accesses
+    let (mut lo, mut hi) = (0, 0);
+
+    use BfIR::*;
+    for (i, &ir) in code.iter().enumerate().skip(1) {
+        match ir {
+            AddVal { offset: o, val } => {
+                let d = deltas.entry(offset.checked_add(o)?).or_default();
+                *d = d.wrapping_add(val);
+            }
+            SubVal { offset: o, val } => {
+                let d = deltas.entry(offset.checked_add(o)?).or_default();
+                *d = d.wrapping_sub(val);
+            }
+            AddPtr(x) => offset = offset.checked_add(i32::try_from(x).ok()?)?,
+            SubPtr(x) => offset = offset.checked_sub(i32::try_from(x).ok()?)?,
+            Jnz if offset == 0 => {
+                // The loop runs `*ptr` times when the cell steps down by one
+                // and `-*ptr` times when it steps up by one.
+                let sign = match deltas.remove(&0) {
+                    Some(u32::MAX) => 1_u32,
+                    Some(1) => u32::MAX,
+                    _ => return None,
+                };
+                deltas.retain(|_, &mut d| d != 0);
+                let first = deltas.keys().next().map_or(0, |&o| o.min(0));
+                let last = deltas.keys().last().map_or(0, |&o| o.max(0));
+                if lo < first || hi > last {
+                    return None;
+                }
+
+                let mut ops: Vec<BfIR> = deltas
+                    .into_iter()
+                    .map(|(offset, d)| MulAdd {
+                        offset,
+                        factor: d.wrapping_mul(sign),
+                    })
+                    .collect();
+                ops.push(SetZero);
+                return Some((i + 1, ops));
+            }
+            _ => return None,
+        }
+        let at = match ir {
+            AddVal { offset: o, .. } | SubVal { offset: o, .. } => offset + o,
+            _ => offset,
+        };
+        (lo, hi) = (lo.min(at), hi.max(at));
+    }
+    None
+}
+
+/// What is known of the cell under the pointer as a program runs, whatever
+/// the cell width.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KnownCell {
+    /// Nothing has been written yet, so every cell is zero.
+    pristine: bool,
+    /// Kept within the range of the narrowest cells, and forgotten when it
+    /// would wrap around.
+    value: Option<u32>,
+}
+
+impl KnownCell {
+    pub(crate) fn new() -> Self {
+        Self {
+            pristine: true,
+            value: Some(0),
+        }
+    }
+
+    /// Knows nothing of the tape, which may be left over from an earlier run.
+    pub(crate) fn unknown() -> Self {
+        Self {
+            pristine: false,
+            value: None,
+        }
+    }
+
+    /// The value of the cell, where it is known.
+    pub(crate) fn value(&self) -> Option<u32> {
+        self.value
+    }
+
+    /// Updates what is known once `ir` has run. A `Jz` that runs leads into
+    /// its loop, on a non-zero cell.
+    pub(crate) fn step(&mut self, ir: BfIR) {
+        let narrow = |v: u32| (v <= u8::MAX as u32).then_some(v);
+
+        use BfIR::*;
+        match ir {
+            AddVal { offset: 0, val } => {
+                self.value = self.value.and_then(|v| v.checked_add(val)).and_then(narrow)
+            }
+            SubVal { offset: 0, val } => self.value = self.value.and_then(|v| v.checked_sub(val)),
+            GetByte { offset: 0 } | Jz => self.value = None,
+            SetValue(val) => self.value = narrow(val),
+            SetZero | Jnz | ScanRight(_) | ScanLeft(_) => self.value = Some(0),
+            AddPtr(_) | SubPtr(_) => self.value = self.pristine.then_some(0),
+            AddVal { .. } | SubVal { .. } | GetByte { .. } | MulAdd { .. } | PutByte { .. } => {}
+        }
+        self.pristine &= !matches!(
+            ir,
+            AddVal { .. } | SubVal { .. } | GetByte { .. } | SetValue(_) | MulAdd { .. }
+        );
+    }
+}
+
+/// Drops loops and other ops that do nothing where the cell under the
+/// pointer is known to be zero, which removes comment loops, and turns a
+/// `SetZero` followed by arithmetic on the cell into a `SetValue`. A run may
+/// start on the tape an earlier one left, so nothing is known at the start;
+/// on a blank tape, [`peval::eval_prefix`](crate::peval::eval_prefix) skips
+/// the opening comment loops instead.
+fn drop_dead_code(code: &mut Vec<BfIR>, positions: &mut Vec<Position>) {
+    let mut out = Vec::with_capacity(code.len());
+    let mut out_positions = Vec::with_capacity(code.len());
+    let mut cell = KnownCell::unknown();
+    let mut i = 0;
+
+    use BfIR::*;
+    while i < code.len() {
+        let (mut ir, pos) = (code[i], positions[i]);
+        let zero = cell.value() == Some(0);
+        match ir {
+            Jz if zero => {
+                let mut depth = 0;
+                loop {
+                    match code[i] {
+                        Jz => depth += 1,
+                        Jnz => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                continue;
+            }
+            SetZero | MulAdd { .. } | ScanRight(_) | ScanLeft(_) if zero => {
+                i += 1;
+                continue;
+            }
+            SetZero => {
+                let val = match code.get(i + 1) {
+                    Some(&AddVal { offset: 0, val }) => Some(val),
+                    Some(&SubVal { offset: 0, val }) => Some(val.wrapping_neg()),
+                    _ => None,
+                };
+                if let Some(val) = val {
+                    ir = SetValue(val);
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+        cell.step(ir);
+        out.push(ir);
+        out_positions.push(pos);
+        i += 1;
+    }
+
+    *code = out;
+    *positions = out_positions;
+}
+
+/// Folds pointer moves into the offsets of the cell accesses that follow
+/// them, so each basic block applies a single net move at its end.
+///
+/// A block is only checked over the cells it accesses and the pointer's end,
+/// so a move that goes past all of those is kept, as a move out and back at
+/// the point the pointer got there.
+fn sink_ptr_moves(code: &mut Vec<BfIR>, positions: &mut Vec<Position>) {
+    let mut out = Vec::with_capacity(code.len());
+    let mut offset: i64 = 0;
+    // where the pending move was last extended
+    let mut move_pos = Position::default();
+    let mut reach = Reach::new(0, 0);
+
+    fn flush_ptr(out: &mut Vec<(BfIR, Position)>, offset: i64, pos: Position) {
+        match offset {
+            0 => {}
+            x if x > 0 => out.push((BfIR::AddPtr(x as u32), pos)),
+            x => out.push((BfIR::SubPtr(x.unsigned_abs() as u32), pos)),
+        }
+    }
+
+    /// Ends the stretch with the pending move, checked where it ends up.
+    fn flush_move(
+        out: &mut Vec<(BfIR, Position)>,
+        reach: &mut Reach,
+        offset: &mut i64,
+        pos: Position,
+    ) {
+        reach.access(*offset);
+        reach.keep(out);
+        flush_ptr(out, std::mem::take(offset), pos);
+        *reach = Reach::new(0, out.len());
+    }
+
+    use BfIR::*;
+    for (&ir, &pos) in code.iter().zip(positions.iter()) {
+        // offsets in the input may already be large, so the pending move goes
+        // out first where adding to it would leave `i32`
+        let to = match ir {
+            AddPtr(x) => offset + x as i64,
+            SubPtr(x) => offset - x as i64,
+            AddVal { offset: d, .. }
+            | SubVal { offset: d, .. }
+            | GetByte { offset: d }
+            | PutByte { offset: d } => offset + d as i64,
+            _ => offset,
+        };
+        if offset != 0 && i32::try_from(to).is_err() {
+            flush_move(&mut out, &mut reach, &mut offset, move_pos);
+        }
+        let o = offset as i32;
+        match ir {
+            AddPtr(x) => {
+                offset += x as i64;
+                move_pos = pos;
+                reach.move_to(offset, out.len(), pos);
+            }
+            SubPtr(x) => {
+                offset -= x as i64;
+                move_pos = pos;
+                reach.move_to(offset, out.len(), pos);
+            }
+            AddVal { offset: d, val } => {
+                reach.access((d + o) as i64);
+                out.push((AddVal { offset: d + o, val }, pos));
+            }
+            SubVal { offset: d, val } => {
+                reach.access((d + o) as i64);
+                out.push((SubVal { offset: d + o, val }, pos));
+            }
+            // the check of a block runs ahead of its first I/O, and no further
+            GetByte { offset: d } => {
+                reach.access((d + o) as i64);
+                out.push((GetByte { offset: d + o }, pos));
+                reach.keep(&mut out);
+                reach = Reach::new(offset, out.len());
+            }
+            PutByte { offset: d } => {
+                reach.access((d + o) as i64);
+                out.push((PutByte { offset: d + o }, pos));
+                reach.keep(&mut out);
+                reach = Reach::new(offset, out.len());
+            }
+            Jz | Jnz | SetZero | SetValue(_) | MulAdd { .. } | ScanRight(_) | ScanLeft(_) => {
+                // the block checks where its net move ends up, too
+                reach.access(offset);
+                reach.keep(&mut out);
+                flush_ptr(&mut out, std::mem::take(&mut offset), move_pos);
+                out.push((ir, pos));
+                reach = Reach::new(0, out.len());
+            }
+        }
+        if i32::try_from(offset).is_err() {
+            flush_move(&mut out, &mut reach, &mut offset, move_pos);
+        }
+    }
+    reach.access(offset);
+    reach.keep(&mut out);
+    flush_ptr(&mut out, offset, move_pos);
+
+    (*code, *positions) = out.into_iter().unzip();
+}
+
+/// How far the pointer strays from the cells a stretch of [`sink_ptr_moves`]
+/// output checks, all relative to where the pointer really is.
+struct Reach {
+    /// The lowest and highest cells that are checked.
+    checked: (i64, i64),
+    /// The lowest and highest the pointer goes, and where in the output and
+    /// the source it first gets there.
+    lo: (i64, usize, Position),
+    hi: (i64, usize, Position),
+}
+
+impl Reach {
+    /// Starts at `offset`, with the real pointer checked too.
+    fn new(offset: i64, at: usize) -> Self {
+        Self {
+            checked: (offset.min(0), offset.max(0)),
+            lo: (offset, at, Position::default()),
+            hi: (offset, at, Position::default()),
+        }
+    }
+
+    fn move_to(&mut self, offset: i64, at: usize, pos: Position) {
+        if offset < self.lo.0 {
+            self.lo = (offset, at, pos);
+        }
+        if offset > self.hi.0 {
+            self.hi = (offset, at, pos);
+        }
+    }
+
+    fn access(&mut self, offset: i64) {
+        self.checked.0 = self.checked.0.min(offset);
+        self.checked.1 = self.checked.1.max(offset);
+    }
+
+    /// Puts a move out and back into `out` for each end that is not checked.
+    fn keep(&self, out: &mut Vec<(BfIR, Position)>) {
+        let mut trips = vec![];
+        if self.lo.0 < self.checked.0 {
+            let x = self.lo.0.unsigned_abs() as u32;
+            trips.push((
+                self.lo.1,
+                [(BfIR::SubPtr(x), self.lo.2), (BfIR::AddPtr(x), self.lo.2)],
+            ));
+        }
+        if self.hi.0 > self.checked.1 {
+            let x = self.hi.0 as u32;
+            trips.push((
+                self.hi.1,
+                [(BfIR::AddPtr(x), self.hi.2), (BfIR::SubPtr(x), self.hi.2)],
+            ));
+        }
+        // the later one first, so the earlier index still holds
+        trips.sort_by_key(|&(at, _)| std::cmp::Reverse(at));
+        for (at, trip) in trips {
+            out.splice(at..at, trip);
+        }
+    }
+}
+
+/// Lists `code` one op per line, with its index and source position, and
+/// loop bodies indented. `positions` may be empty.
+pub fn pretty(code: &[BfIR], positions: &[Position]) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for (pc, &ir) in code.iter().enumerate() {
+        if ir == BfIR::Jnz {
+            depth -= 1;
+        }
+        let pos = positions.get(pc).map_or(String::new(), Position::to_string);
+        out += &format!(
+            "{:>5}  {:<9}{:indent$}{}\n",
+            pc,
+            pos,
+            "",
+            ir,
+            indent = 4 * depth
+        );
+        if ir == BfIR::Jz {
+            depth += 1;
+        }
+    }
+    out
 }
 
 #[test]
@@ -135,25 +828,319 @@ fn test_compile() {
     assert_eq!(
         compile("+[,.]").unwrap(),
         vec![
-            BfIR::AddVal(1),
+            BfIR::AddVal { offset: 0, val: 1 },
             BfIR::Jz,
-            BfIR::GetByte,
-            BfIR::PutByte,
+            BfIR::GetByte { offset: 0 },
+            BfIR::PutByte { offset: 0 },
             BfIR::Jnz,
         ]
     );
 
-    match compile("[").unwrap_err().kind {
+    match compile("[").unwrap_err().errors()[0].kind {
         CompileErrorKind::UnclosedLeftBracket => {}
         _ => panic!(),
     };
 
-    match compile("]").unwrap_err().kind {
+    match compile("]").unwrap_err().errors()[0].kind {
         CompileErrorKind::UnexpectedRightBracket => {}
         _ => panic!(),
     };
 
-    let mut code = compile("[+++++]").unwrap();
+    let mut code = compile(",[+++++]").unwrap();
     optimize(&mut code);
-    assert_eq!(code, vec![BfIR::Jz, BfIR::AddVal(5), BfIR::Jnz]);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::GetByte { offset: 0 },
+            BfIR::Jz,
+            BfIR::AddVal { offset: 0, val: 5 },
+            BfIR::Jnz
+        ]
+    );
+}
+
+#[test]
+fn test_compile_errors() {
+    let e = compile("]\n[ é ] ]\n[").unwrap_err();
+    let at = |line, col| Position { line, col };
+    assert_eq!(
+        e.errors(),
+        [
+            BracketError {
+                kind: CompileErrorKind::UnexpectedRightBracket,
+                pos: at(1, 1),
+                end: None,
+            },
+            BracketError {
+                kind: CompileErrorKind::UnexpectedRightBracket,
+                pos: at(2, 7),
+                end: None,
+            },
+            BracketError {
+                kind: CompileErrorKind::UnclosedLeftBracket,
+                pos: at(3, 1),
+                end: Some(at(3, 2)),
+            },
+        ]
+    );
+
+    let text = e.to_string();
+    assert_eq!(
+        text.lines().collect::<Vec<_>>()[6..],
+        [
+            "error: unexpected right bracket",
+            " --> 2:7",
+            "  |",
+            "2 | [ é ] ]",
+            "  |       ^ no `[` to close",
+            "",
+            "error: unclosed left bracket",
+            " --> 3:1",
+            "  |",
+            "3 | [",
+            "  | ^ this `[` is never closed",
+            "  |  - the program ends here",
+            "",
+            "error: aborting due to 3 previous errors",
+        ]
+    );
+
+    let text = e.with_path("dir/a.bf").to_string();
+    assert_eq!(text.lines().nth(1), Some(" --> dir/a.bf:1:1"));
+}
+
+#[test]
+fn test_optimize_loops() {
+    use BfIR::*;
+
+    let mut code = compile(",[-]>,[+]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            GetByte { offset: 0 },
+            SetZero,
+            GetByte { offset: 1 },
+            AddPtr(1),
+            SetZero
+        ]
+    );
+
+    let mut code = compile(",[->+>++<<]<[>---<+]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            GetByte { offset: 0 },
+            MulAdd {
+                offset: 1,
+                factor: 1
+            },
+            MulAdd {
+                offset: 2,
+                factor: 2
+            },
+            SetZero,
+            SubPtr(1),
+            MulAdd {
+                offset: 1,
+                factor: 3
+            },
+            SetZero,
+        ]
+    );
+
+    // unbalanced or non-unit loops stay as they are, and so do loops going
+    // past the cells they change, as running off the tape there is an error
+    for src in [
+        ",[->+]",
+        ",[--]",
+        ",[-.]",
+        ",[-[-]]",
+        ",[->>+-<<]",
+        ",[-<>]",
+    ] {
+        let mut code = compile(src).unwrap();
+        optimize(&mut code);
+        assert_eq!(code.get(1), Some(&Jz), "{}", src);
+    }
+}
+
+#[test]
+fn test_optimize_offsets() {
+    use BfIR::*;
+
+    let mut code = compile(">>+>--<.<<<,[>>+<<-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            AddVal { offset: 2, val: 1 },
+            SubVal { offset: 3, val: 2 },
+            PutByte { offset: 2 },
+            GetByte { offset: -1 },
+            SubPtr(1),
+            MulAdd {
+                offset: 2,
+                factor: 1
+            },
+            SetZero,
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_large_offsets() {
+    use BfIR::*;
+
+    // the pending move goes out before an offset would leave `i32`
+    let mut code = vec![
+        AddPtr(5),
+        AddVal {
+            offset: i32::MAX,
+            val: 1,
+        },
+    ];
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        [
+            AddPtr(5),
+            AddVal {
+                offset: i32::MAX,
+                val: 1
+            }
+        ]
+    );
+
+    let mut code = vec![SubPtr(5), PutByte { offset: i32::MIN }];
+    optimize(&mut code);
+    assert_eq!(code, [SubPtr(5), PutByte { offset: i32::MIN }]);
+
+    // and before a move would, so it still fits in an op
+    let mut code = vec![
+        AddPtr(i32::MAX as u32),
+        AddVal { offset: 0, val: 1 },
+        AddPtr(u32::MAX),
+    ];
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        [
+            AddVal {
+                offset: i32::MAX,
+                val: 1
+            },
+            AddPtr(i32::MAX as u32),
+            AddPtr(u32::MAX)
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_scans() {
+    use BfIR::*;
+
+    let mut code = compile(",[>]+[<<]>[>>>-<<<]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            GetByte { offset: 0 },
+            ScanRight(1),
+            AddVal { offset: 0, val: 1 },
+            ScanLeft(2),
+            AddPtr(1),
+            Jz,
+            SubVal { offset: 3, val: 1 },
+            Jnz,
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_dead_code() {
+    use BfIR::*;
+
+    let optimized = |src| {
+        let mut code = compile(src).unwrap();
+        optimize(&mut code);
+        code
+    };
+
+    // comment loops after a loop and after `[-]`
+    assert_eq!(
+        optimized(",[.,][never [runs]]."),
+        [
+            GetByte { offset: 0 },
+            Jz,
+            PutByte { offset: 0 },
+            GetByte { offset: 0 },
+            Jnz,
+            PutByte { offset: 0 }
+        ]
+    );
+    assert_eq!(
+        optimized(",[-][>+<-][>]."),
+        [GetByte { offset: 0 }, SetZero, PutByte { offset: 0 }]
+    );
+
+    // a run may start on the tape an earlier one left, so nothing is known of
+    // the cells before the first write, nor of a cell the pointer moves onto
+    assert_eq!(
+        optimized("[a comment, really.]>>[-]<<+"),
+        [
+            Jz,
+            GetByte { offset: 0 },
+            PutByte { offset: 0 },
+            Jnz,
+            AddPtr(2),
+            SetZero,
+            AddVal { offset: -2, val: 1 },
+            SubPtr(2)
+        ]
+    );
+    assert_eq!(
+        optimized(",[-]>[a comment]"),
+        [GetByte { offset: 0 }, SetZero, AddPtr(1), Jz, Jnz]
+    );
+
+    // cancelling pairs, also across a dropped loop
+    assert_eq!(
+        optimized(",[-]+-[a comment],"),
+        [GetByte { offset: 0 }, SetZero, GetByte { offset: 0 }]
+    );
+    assert_eq!(
+        optimized(",+--"),
+        [GetByte { offset: 0 }, SubVal { offset: 0, val: 1 }]
+    );
+
+    // `[-]` then arithmetic sets the cell
+    assert_eq!(
+        optimized(",[-]+++.[-]-.>"),
+        [
+            GetByte { offset: 0 },
+            SetValue(3),
+            PutByte { offset: 0 },
+            SetValue(u32::MAX),
+            PutByte { offset: 0 },
+            AddPtr(1)
+        ]
+    );
+}
+
+#[test]
+fn test_pretty() {
+    let (code, positions) = compile_with_positions("+[\n>.]").unwrap();
+    let text = pretty(&code, &positions);
+    assert_eq!(
+        text.lines().collect::<Vec<_>>(),
+        [
+            "    0  1:1      p[0] += 1",
+            "    1  1:2      while p[0] {",
+            "    2  2:1          p += 1",
+            "    3  2:2          putchar(p[0])",
+            "    4  2:3      }",
+        ]
+    );
 }
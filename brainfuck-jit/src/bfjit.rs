@@ -1,21 +1,94 @@
-use crate::bfir::{self, BfIR};
+use crate::analysis;
+use crate::bfinterp::Interpreter;
+use crate::bfir::{self, BfIR, Position};
+use crate::codegen::{self, Asm, Runtime};
+use crate::config::{EofPolicy, VmConfig};
 use crate::error::{Result, RuntimeError, VMError};
+use crate::snapshot::{self, Snapshot};
+use crate::tape::{self, Fence, Tape};
 
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::components::StaticLabel;
+use dynasmrt::DynasmApi;
 
-const MEMORY_SIZE: usize = 4 * 1024 * 1024;
+type Assembler = dynasmrt::x64::Assembler;
+
+pub(crate) const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+/// Bytes printed by `.` but not yet written to the output. The JIT appends
+/// to it inline and only calls out once it fills up.
+#[repr(C)]
+pub(crate) struct OutputBuffer {
+    len: usize,
+    pub(crate) data: [u8; OUTPUT_BUFFER_SIZE],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Backend {
+    /// Walk the IR directly
+    Interp,
+    /// Emit x86-64 machine code with dynasm
+    #[default]
+    Jit,
+}
+
+enum Engine {
+    Interp(Rc<Interpreter>),
+    Jit {
+        code: dynasmrt::ExecutableBuffer,
+        start: dynasmrt::AssemblyOffset,
+        /// Offset in `code` of the first instruction of each op, and then
+        /// of the epilogue and of the data after it.
+        offsets: Vec<usize>,
+        ir: Vec<BfIR>,
+        /// Bytes of guard pages the code relies on, or 0.
+        guard: usize,
+        overflow: dynasmrt::AssemblyOffset,
+    },
+}
+
+impl Engine {
+    fn ir(&self) -> &[BfIR] {
+        match self {
+            Engine::Interp(interp) => interp.code(),
+            Engine::Jit { ir, .. } => ir,
+        }
+    }
+}
+
+/// Where the JIT enters, and what it checks at each `]`.
+#[repr(C)]
+struct Control {
+    /// Jumps left, if [`VmConfig::budget`] was set.
+    budget: u64,
+    interrupt: *const AtomicBool,
+    /// Code to start at instead of the first op.
+    entry: *const u8,
+}
 
 pub struct BfVM<'io> {
-    code: dynasmrt::ExecutableBuffer,
-    start: dynasmrt::AssemblyOffset,
-    memory: Box<[u8]>,
+    engine: Engine,
+    pub(crate) config: VmConfig,
+    /// Source position of each op, or empty if unknown.
+    positions: Vec<Position>,
+    pub(crate) memory: Tape,
     input: Box<dyn Read + 'io>,
     output: Box<dyn Write + 'io>,
+    output_buffer: Box<OutputBuffer>,
+    control: Box<Control>,
+    /// The op the next run starts at, and the cell the pointer is on.
+    pub(crate) pc: usize,
+    pub(crate) ptr: usize,
+    /// Whether the tape is still blank, so a run may skip the part of the
+    /// program that was evaluated at compile time.
+    pub(crate) fresh: bool,
 }
 
 #[inline(always)]
@@ -25,73 +98,503 @@ fn vm_error(re: RuntimeError) -> *mut VMError {
 }
 
 impl<'io> BfVM<'io> {
-    unsafe extern "sysv64" fn get_byte(this: *mut Self, ptr: *mut u8) -> *mut VMError {
+    pub(crate) fn read_byte(&mut self) -> std::result::Result<Option<u8>, RuntimeError> {
         let mut buf = [0_u8];
+        match self.input.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(1) => Ok(Some(buf[0])),
+            Ok(_) => unreachable!(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(RuntimeError::NeedInput),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the value `,` stores, or `None` to leave the cell unchanged.
+    pub(crate) fn read_cell(&mut self) -> std::result::Result<Option<u32>, RuntimeError> {
+        Ok(match self.read_byte()? {
+            Some(b) => Some(b as u32),
+            None => match self.config.eof {
+                EofPolicy::Unchanged => None,
+                EofPolicy::Zero => Some(0),
+                EofPolicy::MinusOne => Some(u32::MAX),
+            },
+        })
+    }
+
+    pub(crate) fn write_byte(&mut self, byte: u8) -> std::result::Result<(), RuntimeError> {
+        let buffer = &mut *self.output_buffer;
+        buffer.data[buffer.len] = byte;
+        buffer.len += 1;
+        if buffer.len == OUTPUT_BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// Hands the buffered bytes to the output.
+    fn write_buffer(&mut self) -> std::result::Result<(), RuntimeError> {
+        let buffer = &mut *self.output_buffer;
+        let len = std::mem::take(&mut buffer.len);
+        self.output.write_all(&buffer.data[..len])?;
+        Ok(())
+    }
+
+    /// Checks the interrupt flag and spends one jump of the budget.
+    pub(crate) fn back_edge(&mut self) -> std::result::Result<(), RuntimeError> {
+        // checks fail before changing anything, so the `]` can run again
+        if let Some(flag) = &self.config.interrupt {
+            if flag.load(Ordering::Relaxed) {
+                return Err(RuntimeError::Interrupted);
+            }
+        }
+        if self.config.budget.is_some() {
+            let budget = &mut self.control.budget;
+            *budget = budget.checked_sub(1).ok_or(RuntimeError::Timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Attaches the source position of the op at `op` to a pointer overflow.
+    pub(crate) fn locate(&self, e: RuntimeError, op: usize) -> RuntimeError {
+        match e {
+            RuntimeError::PointerOverflow { ptr, pos: None } => RuntimeError::PointerOverflow {
+                ptr,
+                pos: self.positions.get(op).copied(),
+            },
+            e => e,
+        }
+    }
+
+    pub(crate) fn flush_output(&mut self) -> std::result::Result<(), RuntimeError> {
+        self.write_buffer()?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    unsafe extern "sysv64" fn get_byte(
+        this: *mut Self,
+        cell: *mut u8,
+        addr: *const u8,
+        ptr: *const u8,
+    ) -> *mut VMError {
         let this = &mut *this;
-        match this.input.read(&mut buf) {
-            Ok(0) => {}
-            Ok(1) => *ptr = buf[0],
-            Err(e) => return vm_error(RuntimeError::IO(e)),
-            _ => unreachable!(),
+        match this.read_cell() {
+            Ok(Some(val)) => {
+                let val = val.to_le_bytes();
+                let len = this.config.cell_width.bytes();
+                ptr::copy_nonoverlapping(val.as_ptr(), cell, len);
+            }
+            Ok(None) => {}
+            Err(RuntimeError::NeedInput) => {
+                this.pause(addr, ptr);
+                return vm_error(RuntimeError::NeedInput);
+            }
+            Err(e) => return vm_error(e),
         }
         ptr::null_mut()
     }
 
-    unsafe extern "sysv64" fn put_byte(this: *mut Self, ptr: *const u8) -> *mut VMError {
-        let buf = std::slice::from_ref(&*ptr);
+    unsafe extern "sysv64" fn flush_buffer(this: *mut Self) -> *mut VMError {
         let this = &mut *this;
-        match this.output.write_all(buf) {
+        match this.flush_output() {
             Ok(()) => ptr::null_mut(),
-            Err(e) => vm_error(RuntimeError::IO(e)),
+            Err(e) => vm_error(e),
         }
     }
 
-    unsafe extern "sysv64" fn overflow_error() -> *mut VMError {
-        vm_error(RuntimeError::PointerOverflow)
+    unsafe extern "sysv64" fn timeout_error(
+        this: *mut Self,
+        addr: *const u8,
+        ptr: *const u8,
+    ) -> *mut VMError {
+        (*this).pause(addr, ptr);
+        vm_error(RuntimeError::Timeout)
+    }
+
+    unsafe extern "sysv64" fn interrupted_error(
+        this: *mut Self,
+        addr: *const u8,
+        ptr: *const u8,
+    ) -> *mut VMError {
+        (*this).pause(addr, ptr);
+        vm_error(RuntimeError::Interrupted)
+    }
+
+    /// Records where compiled code stopped, so the next run resumes there.
+    fn pause(&mut self, addr: *const u8, ptr: *const u8) {
+        self.pc = self.op_at(addr);
+        self.ptr = self.cell_at(ptr) as usize;
+    }
+
+    /// Index of the op whose code contains `addr`.
+    fn op_at(&self, addr: *const u8) -> usize {
+        let Engine::Jit { code, offsets, .. } = &self.engine else {
+            unreachable!()
+        };
+        let offset = addr as usize - code.ptr(dynasmrt::AssemblyOffset(0)) as usize;
+        offsets.partition_point(|&o| o < offset) - 1
+    }
+
+    /// Index of the cell at `ptr`, which may lie off the tape.
+    fn cell_at(&self, ptr: *const u8) -> i64 {
+        let bytes = ptr as isize - self.memory.as_ptr() as isize;
+        bytes as i64 / self.config.cell_width.bytes() as i64
+    }
+
+    unsafe extern "sysv64" fn overflow_error(
+        this: *mut Self,
+        addr: *const u8,
+        ptr: *const u8,
+    ) -> *mut VMError {
+        let this = &mut *this;
+        let Engine::Jit { ir, .. } = &this.engine else {
+            unreachable!()
+        };
+
+        // the call sits inside the code of the op whose check failed
+        let first = this.op_at(addr);
+        let cell = this.cell_at(ptr);
+        let (op, cell) = codegen::overflow_site(&ir[first..], cell, this.config.tape_size);
+
+        let e = RuntimeError::PointerOverflow {
+            ptr: cell,
+            pos: None,
+        };
+        vm_error(this.locate(e, first + op))
     }
 }
 
 impl<'io> BfVM<'io> {
+    /// Reads and compiles the program at `file_path`.
     pub fn new(
         file_path: &Path,
         input: Box<dyn Read + 'io>,
         output: Box<dyn Write + 'io>,
         optimize: bool,
+        config: VmConfig,
     ) -> Result<Self> {
         let src = std::fs::read_to_string(file_path)?;
-        let mut ir = bfir::compile(&src)?;
-        drop(src);
+        Self::from_source(&src, input, output, optimize, config).map_err(|e| match e {
+            VMError::Compile(e) => VMError::Compile(e.with_path(file_path)),
+            e => e,
+        })
+    }
 
+    /// Compiles a program from Brainfuck source text.
+    pub fn from_source(
+        src: &str,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        optimize: bool,
+        config: VmConfig,
+    ) -> Result<Self> {
+        let (mut ir, mut positions) = bfir::compile_with_positions(src)?;
         if optimize {
-            bfir::optimize(&mut ir);
+            bfir::optimize_with_positions(&mut ir, &mut positions);
         }
-        let (code, start) = Self::compile(&ir)?;
-        drop(ir);
+        Self::from_ir_with_positions(ir, positions, input, output, config)
+    }
+
+    /// Builds a VM from IR produced by [`bfir::compile`] and, optionally,
+    /// [`bfir::optimize`]. Fails if the loops in `ir` are not balanced.
+    pub fn from_ir(
+        ir: Vec<BfIR>,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        config: VmConfig,
+    ) -> Result<Self> {
+        Self::from_ir_with_positions(ir, vec![], input, output, config)
+    }
+
+    /// Like [`BfVM::from_ir`], with the source position of each op in `ir`
+    /// for runtime errors to report.
+    pub fn from_ir_with_positions(
+        ir: Vec<BfIR>,
+        positions: Vec<Position>,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        config: VmConfig,
+    ) -> Result<Self> {
+        config.validate().map_err(VMError::InvalidConfig)?;
+        check_loops(&ir)?;
+
+        let engine = match config.backend {
+            Backend::Interp => Engine::Interp(Rc::new(Interpreter::with_prefix(ir, &config))),
+            Backend::Jit => Self::compile(ir, &config)?,
+        };
+        Self::with_engine(engine, positions, input, output, config)
+    }
 
-        let memory = vec![0; MEMORY_SIZE].into_boxed_slice();
+    /// Builds a VM on the interpreter `interp`, for a caller that steps it
+    /// itself.
+    pub(crate) fn with_interpreter(
+        interp: Rc<Interpreter>,
+        positions: Vec<Position>,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        config: VmConfig,
+    ) -> Result<Self> {
+        config.validate().map_err(VMError::InvalidConfig)?;
+        let config = config.backend(Backend::Interp);
+        Self::with_engine(Engine::Interp(interp), positions, input, output, config)
+    }
+
+    fn with_engine(
+        engine: Engine,
+        positions: Vec<Position>,
+        input: Box<dyn Read + 'io>,
+        output: Box<dyn Write + 'io>,
+        config: VmConfig,
+    ) -> Result<Self> {
+        let tape_bytes = config.tape_bytes().unwrap();
+        let memory = match engine {
+            Engine::Jit { guard, .. } if guard > 0 => Tape::guarded(tape_bytes, guard)?,
+            _ => Tape::Heap(vec![0; tape_bytes].into_boxed_slice()),
+        };
+        let control = Box::new(Control {
+            budget: config.budget.unwrap_or(0),
+            interrupt: config.interrupt.as_ref().map_or(ptr::null(), Arc::as_ptr),
+            entry: ptr::null(),
+        });
+        let origin = config.tape_origin;
         Ok(Self {
-            code,
-            start,
+            engine,
+            config,
+            positions,
             memory,
             input,
             output,
+            output_buffer: Box::new(OutputBuffer {
+                len: 0,
+                data: [0; OUTPUT_BUFFER_SIZE],
+            }),
+            control,
+            pc: 0,
+            ptr: origin,
+            fresh: true,
         })
     }
 
+    /// Runs the program from the start, or from where a
+    /// [`Timeout`](RuntimeError::Timeout), an
+    /// [`Interrupted`](RuntimeError::Interrupted) or a
+    /// [`NeedInput`](RuntimeError::NeedInput) paused it.
     pub fn run(&mut self) -> Result<()> {
+        let ret = match &self.engine {
+            Engine::Interp(interp) => Rc::clone(interp).run(self),
+            Engine::Jit { .. } => self.run_jit(),
+        };
+        self.fresh = false;
+        if !matches!(
+            ret,
+            Err(VMError::Runtime(
+                RuntimeError::Timeout | RuntimeError::Interrupted | RuntimeError::NeedInput
+            ))
+        ) {
+            // the next run starts over
+            self.pc = 0;
+            self.ptr = self.config.tape_origin;
+        }
+        // keep whatever was printed before an error
+        let flushed = self.flush_output();
+        ret?;
+        Ok(flushed?)
+    }
+
+    /// Index of the op the next [`run`](Self::run) starts at: 0, or the `]`
+    /// or `,` a paused run stopped at.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The cell the pointer is on between runs.
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// The tape as little-endian cells.
+    pub fn tape(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Disassembles the compiled code, headed op by op with the op's index,
+    /// source position and [`BfIR`] text. The prologue also holds the work
+    /// done at compile time, and the data after the epilogue what that work
+    /// prints. Returns `None` on the interpreter.
+    pub fn disassemble(&self) -> Option<String> {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, NasmFormatter};
+
+        let Engine::Jit {
+            code, offsets, ir, ..
+        } = &self.engine
+        else {
+            return None;
+        };
+
+        let mut formatter = NasmFormatter::new();
+        formatter.options_mut().set_branch_leading_zeros(false);
+        let mut out = String::new();
+        let mut text = String::new();
+        let mut instr = Instruction::default();
+        let mut section = |out: &mut String, header: String, range: std::ops::Range<usize>| {
+            *out += &format!("; {}\n", header);
+            let mut decoder = Decoder::with_ip(
+                64,
+                &code[range.clone()],
+                range.start as u64,
+                DecoderOptions::NONE,
+            );
+            while decoder.can_decode() {
+                decoder.decode_out(&mut instr);
+                text.clear();
+                formatter.format(&instr, &mut text);
+                *out += &format!("{:6x}  {}\n", instr.ip(), text);
+            }
+        };
+
+        section(&mut out, "prologue".to_string(), 0..offsets[0]);
+        for (pc, &op) in ir.iter().enumerate() {
+            let pos = self
+                .positions
+                .get(pc)
+                .map_or(String::new(), |p| format!(" {}", p));
+            section(
+                &mut out,
+                format!("{}{}  {}", pc, pos, op),
+                offsets[pc]..offsets[pc + 1],
+            );
+        }
+        section(
+            &mut out,
+            "epilogue".to_string(),
+            offsets[ir.len()]..offsets[ir.len() + 1],
+        );
+
+        let data = offsets[ir.len() + 1];
+        if data < code.len() {
+            out += "; data\n";
+        }
+        for (i, line) in code[data..].chunks(16).enumerate() {
+            let bytes: Vec<_> = line.iter().map(|b| format!("{:02x}h", b)).collect();
+            out += &format!("{:6x}  db {}\n", data + 16 * i, bytes.join(","));
+        }
+        Some(out)
+    }
+
+    /// Refills the budget, so a run that timed out can go on. Does nothing
+    /// unless the config set a [`budget`](VmConfig::budget).
+    pub fn set_budget(&mut self, jumps: u64) {
+        self.control.budget = jumps;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cell_width: self.config.cell_width,
+            fingerprint: snapshot::fingerprint(self.engine.ir()),
+            pc: self.pc,
+            ptr: self.ptr,
+            tape: Box::from(&self.memory[..]),
+        }
+    }
+
+    /// Loads a snapshot taken from a VM with the same program, cell width and
+    /// tape size, so the next run resumes from it.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let invalid = VMError::InvalidSnapshot;
+        let ir = self.engine.ir();
+        if snapshot.fingerprint != snapshot::fingerprint(ir) {
+            return Err(invalid("taken from another program"));
+        }
+        if snapshot.cell_width != self.config.cell_width {
+            return Err(invalid("cell width differs"));
+        }
+        if snapshot.tape.len() != self.memory.len() {
+            return Err(invalid("tape size differs"));
+        }
+        if snapshot.ptr >= self.config.tape_size {
+            return Err(invalid("ptr is off the tape"));
+        }
+        let ptr = snapshot.ptr as i64;
+        // compiled code resumes past the bounds checks
+        match ir.get(snapshot.pc) {
+            _ if snapshot.pc == 0 => {}
+            Some(BfIR::Jnz) => {}
+            Some(&BfIR::GetByte { offset }) => {
+                let cell = snapshot.ptr as i64 + offset as i64;
+                if !(0..self.config.tape_size as i64).contains(&cell) {
+                    return Err(invalid("ptr is off the tape"));
+                }
+            }
+            _ => return Err(invalid("pc is not at a `]` or `,`")),
+        }
+        // nor can compiled code check what the analysis proved safe
+        let ranges = analysis::ptr_ranges(ir, self.config.tape_origin, self.config.tape_size);
+        if !ranges[snapshot.pc].is_some_and(|r| r.lo <= ptr && ptr <= r.hi) {
+            return Err(invalid("ptr is not where the program can be at pc"));
+        }
+
+        self.memory.copy_from_slice(&snapshot.tape);
+        self.fresh = false;
+        self.pc = snapshot.pc;
+        self.ptr = snapshot.ptr;
+        Ok(())
+    }
+
+    fn run_jit(&mut self) -> Result<()> {
+        let Engine::Jit {
+            code,
+            start,
+            offsets,
+            overflow,
+            ..
+        } = &self.engine
+        else {
+            unreachable!()
+        };
+
         type RawFn = unsafe extern "sysv64" fn(
             this: *mut BfVM<'_>,
             memory_start: *mut u8,
             memory_end: *const u8,
+            ptr: *mut u8,
+            output_buffer: *mut OutputBuffer,
+            control: *mut Control,
         ) -> *mut VMError;
 
-        let raw_fn: RawFn = unsafe { std::mem::transmute(self.code.ptr(self.start)) };
+        let raw_fn: RawFn = unsafe { std::mem::transmute(code.ptr(*start)) };
+        self.control.entry = match self.pc {
+            // the code starts with the work done at compile time
+            0 if self.fresh => ptr::null(),
+            pc => offsets
+                .get(pc)
+                .map_or(ptr::null(), |&o| code.ptr(dynasmrt::AssemblyOffset(o))),
+        };
+        let fence = match &self.memory {
+            Tape::Heap(_) => None,
+            guarded => {
+                let base = code.ptr(dynasmrt::AssemblyOffset(0)) as usize;
+                Some(Fence {
+                    code: base..base + code.len(),
+                    guards: guarded.guards(),
+                    overflow: code.ptr(*overflow) as usize,
+                })
+            }
+        };
 
         let this: *mut Self = self;
+        let cell_bytes = self.config.cell_width.bytes();
         let memory_start = self.memory.as_mut_ptr();
-        let memory_end = unsafe { memory_start.add(MEMORY_SIZE) };
+        let memory_end = unsafe { memory_start.add(self.memory.len()) };
+        let ptr = unsafe { memory_start.add(self.ptr * cell_bytes) };
 
-        let ret: *mut VMError = unsafe { raw_fn(this, memory_start, memory_end) };
+        let output_buffer: *mut OutputBuffer = &mut *self.output_buffer;
+        let control: *mut Control = &mut *self.control;
+
+        let run = || unsafe { raw_fn(this, memory_start, memory_end, ptr, output_buffer, control) };
+        let ret: *mut VMError = match &fence {
+            Some(fence) => tape::fenced(fence, run),
+            None => run(),
+        };
 
         if ret.is_null() {
             Ok(())
@@ -102,104 +605,666 @@ impl<'io> BfVM<'io> {
 }
 
 impl<'io> BfVM<'io> {
-    #[allow(clippy::fn_to_numeric_cast)]
-    fn compile(code: &[BfIR]) -> Result<(dynasmrt::ExecutableBuffer, dynasmrt::AssemblyOffset)> {
-        let mut ops = dynasmrt::x64::Assembler::new()?;
+    fn compile(ir: Vec<BfIR>, config: &VmConfig) -> Result<Engine> {
+        let mut ops = Assembler::new()?;
         let start = ops.offset();
 
-        let mut loops = vec![];
+        let guard = match config.guard_pages {
+            true => {
+                // past the farthest any op reaches, so that reach faults
+                let page = tape::page_size();
+                let reach = codegen::max_reach(&ir);
+                let bytes = reach.saturating_mul(config.cell_width.bytes() as u64);
+                let pages = (bytes / page as u64).saturating_add(1);
+                pages
+                    .saturating_mul(page as u64)
+                    .min(tape::MAX_GUARD as u64) as usize
+            }
+            false => 0,
+        };
+        let rt = JitRuntime {
+            budget: config.budget.is_some(),
+            interrupt: config.interrupt.is_some(),
+            guard,
+        };
+        let offsets = codegen::compile(&mut ops, &rt, &ir, config);
+        let overflow = ops
+            .labels()
+            .resolve_static(&StaticLabel::global("overflow"))
+            .unwrap();
+
+        let code = ops.finalize().unwrap();
+
+        Ok(Engine::Jit {
+            code,
+            start,
+            offsets,
+            ir,
+            guard,
+            overflow,
+        })
+    }
+}
 
-        // this:         rdi r12
-        // memory_start: rsi r13
-        // memory_end:   rdx r14
-        // ptr:          rcx r15
+/// Runs compiled code inside the host process, calling back into the
+/// [`BfVM`] for I/O.
+struct JitRuntime {
+    /// Whether to check the budget and the interrupt flag in the `Control`.
+    budget: bool,
+    interrupt: bool,
+    guard: usize,
+}
+
+impl Runtime for JitRuntime {
+    fn prologue<D: Asm>(&self, ops: &mut D) {
+        // this:          rdi r12
+        // memory_start:  rsi r13
+        // memory_end:    rdx r14
+        // ptr:           rcx r15
+        // output_buffer: r8  rbx
+        // control:       r9  [rsp]
 
         dynasm!(ops
-            ; push rax
+            ; push rbx
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+            ; push r9
+            ; push r9        // keeps the stack aligned
             ; mov r12, rdi   // save this
             ; mov r13, rsi   // save memory_start
             ; mov r14, rdx   // save memory_end
-            ; mov rcx, rsi   // ptr = memory_start
+            ; mov rbx, r8    // save output_buffer
+            ; mov rax, QWORD [r9 + 16]
+            ; test rax, rax
+            ; jz >start
+            ; jmp rax        // resume at control.entry
+            ; start:
         );
+    }
 
-        use BfIR::*;
-        for &ir in code {
-            match ir {
-                AddPtr(x) => dynasm!(ops
-                    ; add rcx, x as i32     // ptr += x
-                    ; jc  ->overflow        // jmp if overflow
-                    ; cmp rcx, r14          // ptr - memory_end
-                    ; jnb ->overflow        // jmp if ptr >= memory_end
-                ),
-                SubPtr(x) => dynasm!(ops
-                    ; sub rcx, x as i32     // ptr -= x
-                    ; jc  ->overflow        // jmp if overflow
-                    ; cmp rcx, r13          // ptr - memory_start
-                    ; jb  ->overflow        // jmp if ptr < memory_start
-                ),
-                AddVal(x) => dynasm!(ops
-                    ; add BYTE [rcx], x as i8    // *ptr += x
-                ),
-                SubVal(x) => dynasm!(ops
-                    ; sub BYTE [rcx], x as i8    // *ptr -= x
-                ),
-                GetByte => dynasm!(ops
-                    ; mov  r15, rcx         // save ptr
-                    ; mov  rdi, r12
-                    ; mov  rsi, rcx         // arg0: this, arg1: ptr
-                    ; mov  rax, QWORD BfVM::get_byte as _
-                    ; call rax              // getbyte(this, ptr)
-                    ; test rax, rax
-                    ; jnz  ->io_error       // jmp if rax != 0
-                    ; mov  rcx, r15         // recover ptr
-                ),
-                PutByte => dynasm!(ops
-                    ; mov  r15, rcx         // save ptr
-                    ; mov  rdi, r12
-                    ; mov  rsi, rcx         // arg0: this, arg1: ptr
-                    ; mov  rax, QWORD BfVM::put_byte as _
-                    ; call rax              // putbyte(this, ptr)
-                    ; test rax, rax
-                    ; jnz  ->io_error       // jmp if rax != 0
-                    ; mov  rcx, r15         // recover ptr
-                ),
-                Jz => {
-                    let left = ops.new_dynamic_label();
-                    let right = ops.new_dynamic_label();
-                    loops.push((left, right));
-
-                    dynasm!(ops
-                        ; cmp BYTE [rcx], 0
-                        ; jz => right       // jmp if *ptr == 0
-                        ; => left
-                    )
-                }
-                Jnz => {
-                    let (left, right) = loops.pop().unwrap();
-                    dynasm!(ops
-                        ; cmp BYTE [rcx], 0
-                        ; jnz => left       // jmp if *ptr != 0
-                        ; => right
-                    )
-                }
-            }
+    fn get_byte<D: Asm>(&self, ops: &mut D) {
+        dynasm!(ops
+            ; here:
+            ; mov  rdi, r12         // arg0: this, arg1: ptr + offset
+            ; lea  rdx, [<here]     // arg2: the `,` to resume at
+                                    // arg3: ptr
+            ; mov  rax, QWORD BfVM::get_byte as *const () as _
+            ; call rax              // getbyte(this, ptr + offset, here, ptr)
+            ; test rax, rax
+            ; jnz  ->io_error       // jmp if rax != 0
+        );
+    }
+
+    fn flush<D: Asm>(&self, ops: &mut D) {
+        dynasm!(ops
+            ; mov  rdi, r12         // arg0: this
+            ; mov  rax, QWORD BfVM::flush_buffer as *const () as _
+            ; call rax              // flush_buffer(this)
+            ; test rax, rax
+            ; jnz  ->io_error       // jmp if rax != 0
+        );
+    }
+
+    fn guard(&self) -> usize {
+        self.guard
+    }
+
+    fn back_edge<D: Asm>(&self, ops: &mut D) {
+        if self.budget || self.interrupt {
+            dynasm!(ops
+                ; mov rax, QWORD [rsp]      // control
+            );
         }
+        if self.interrupt {
+            dynasm!(ops
+                ; mov  rdx, QWORD [rax + 8] // interrupt
+                ; cmp  BYTE [rdx], 0
+                ; je   >ok
+                ; call ->interrupted        // if the flag is set
+                ; ok:
+            );
+        }
+        if self.budget {
+            dynasm!(ops
+                ; cmp  QWORD [rax], 0       // budget
+                ; jne  >ok
+                ; call ->timeout            // if the budget is spent
+                ; ok:
+                ; sub  QWORD [rax], 1
+            );
+        }
+    }
 
+    fn epilogue<D: Asm>(&self, ops: &mut D) {
         dynasm!(ops
             ; xor rax, rax
             ; jmp >exit
             ; -> overflow:
-            ; mov rax, QWORD BfVM::overflow_error as _
+            ; pop  rsi              // arg1: where the check failed
+            ; mov  rdx, rcx         // arg2: ptr
+            ; mov  rdi, r12         // arg0: this
+            ; mov  rax, QWORD BfVM::overflow_error as *const () as _
+            ; call rax
+            ; jmp >exit
+            ; -> timeout:
+            ; pop  rsi              // arg1: the `]` to resume at
+            ; mov  rdx, rcx         // arg2: ptr
+            ; mov  rdi, r12         // arg0: this
+            ; mov  rax, QWORD BfVM::timeout_error as *const () as _
+            ; call rax
+            ; jmp >exit
+            ; -> interrupted:
+            ; pop  rsi
+            ; mov  rdx, rcx
+            ; mov  rdi, r12
+            ; mov  rax, QWORD BfVM::interrupted_error as *const () as _
             ; call rax
             ; jmp >exit
             ; -> io_error:
             ; exit:
-            ; pop rdx
+            ; add rsp, 16
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbx
             ; ret
         );
+    }
+}
 
-        let code = ops.finalize().unwrap();
+pub(crate) fn check_loops(code: &[BfIR]) -> Result<()> {
+    let mut depth = 0_usize;
+    for (pc, &ir) in code.iter().enumerate() {
+        match ir {
+            BfIR::Jz => depth += 1,
+            BfIR::Jnz => depth = depth.checked_sub(1).ok_or(VMError::UnbalancedLoop(pc))?,
+            _ => {}
+        }
+    }
+    match depth {
+        0 => Ok(()),
+        _ => Err(VMError::UnbalancedLoop(code.len())),
+    }
+}
+
+#[test]
+fn test_from_ir() {
+    use crate::bfio::{input_from, output_to};
+    use BfIR::*;
+
+    let ir = vec![
+        AddVal { offset: 0, val: 3 },
+        Jz,
+        AddVal { offset: 1, val: 11 },
+        SubVal { offset: 0, val: 1 },
+        Jnz,
+        PutByte { offset: 1 },
+    ];
+    for backend in [Backend::Interp, Backend::Jit] {
+        let mut output = vec![];
+        BfVM::from_ir(
+            ir.clone(),
+            input_from(""),
+            output_to(&mut output),
+            VmConfig::new().backend(backend),
+        )
+        .and_then(|mut vm| vm.run())
+        .unwrap();
+        assert_eq!(output, b"!");
+    }
+
+    for ir in [vec![Jz], vec![Jnz, Jz]] {
+        match BfVM::from_ir(ir, input_from(""), Box::new(vec![]), VmConfig::new()) {
+            Err(VMError::UnbalancedLoop(_)) => {}
+            _ => panic!(),
+        }
+    }
+}
+
+#[test]
+fn test_output_buffer() {
+    use std::cell::RefCell;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Checks that the prompt has been written before `,` reads.
+    struct Prompted(Shared);
+
+    impl Read for Prompted {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            assert_eq!(self.0 .0.borrow().last(), Some(&b'?'));
+            buf[0] = b'!';
+            Ok(1)
+        }
+    }
+
+    // more output than fits in the buffer, then a prompt and a read
+    let src = format!(">{}[<+[.+]>-]<{}.,.", "+".repeat(20), "+".repeat(63));
+    for backend in [Backend::Interp, Backend::Jit] {
+        let output = Shared::default();
+        let config = VmConfig::new().backend(backend);
+        BfVM::from_source(
+            &src,
+            Box::new(Prompted(output.clone())),
+            Box::new(output.clone()),
+            true,
+            config,
+        )
+        .and_then(|mut vm| vm.run())
+        .unwrap();
+
+        let output = output.0.borrow();
+        assert_eq!(output.len(), 20 * 255 + 2);
+        assert_eq!(&output[20 * 255 - 2..], b"\xfe\xff?!");
+    }
+}
 
-        Ok((code, start))
+#[test]
+fn test_overflow_position() {
+    use crate::bfio::input_from;
+
+    let cases = [
+        ("+>+\n<<", false, (2, 2), -1),
+        ("+>+\n<<", true, (2, 1), -1),
+        ("+[<+>-]", false, (1, 3), -1),
+        ("+[<+>-]", true, (1, 2), -1),
+        ("+>+>+>+>+>+>+>+<<<<<<<[>]", false, (1, 24), 8),
+        ("+>+>+>+>+>+>+>+<<<<<<<[>]", true, (1, 23), 8),
+        ("\u{e9}+>>.<<<", false, (1, 8), -1),
+        ("\u{e9}+>>.<<<", true, (1, 6), -1),
+        // optimizing keeps a move that comes straight back
+        ("<>", false, (1, 1), -1),
+        ("<>", true, (1, 1), -1),
+        ("+.>>>>>>>><<<<<<<<.", false, (1, 10), 8),
+        ("+.>>>>>>>><<<<<<<<.", true, (1, 3), 8),
+    ];
+    for (src, optimize, (line, col), ptr) in cases {
+        for backend in [Backend::Interp, Backend::Jit] {
+            let ret = BfVM::from_source(
+                src,
+                input_from(""),
+                Box::new(vec![]),
+                optimize,
+                VmConfig::new().backend(backend).tape_size(8),
+            )
+            .and_then(|mut vm| vm.run());
+            match ret {
+                Err(VMError::Runtime(RuntimeError::PointerOverflow { ptr: p, pos })) => {
+                    assert_eq!(pos, Some(Position { line, col }), "{} {:?}", src, backend);
+                    assert_eq!(p, ptr, "{} {:?}", src, backend);
+                }
+                _ => panic!("{} {:?}", src, backend),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_limits() {
+    use crate::bfio::{input_from, output_to};
+
+    fn run(src: &str, optimize: bool, config: VmConfig) -> Result<Vec<u8>> {
+        let mut output = vec![];
+        BfVM::from_source(
+            src,
+            input_from(""),
+            output_to(&mut output),
+            optimize,
+            config,
+        )
+        .and_then(|mut vm| vm.run())?;
+        Ok(output)
+    }
+
+    for backend in [Backend::Interp, Backend::Jit] {
+        let config = VmConfig::new().backend(backend);
+        for optimize in [false, true] {
+            assert_eq!(
+                run("+++[.-]", optimize, config.clone().budget(3)).unwrap(),
+                [3, 2, 1]
+            );
+            match run("+++[.-]", optimize, config.clone().budget(2)) {
+                Err(VMError::Runtime(RuntimeError::Timeout)) => {}
+                ret => panic!("{:?}", ret),
+            }
+            match run("+[]", optimize, config.clone().budget(100_000)) {
+                Err(VMError::Runtime(RuntimeError::Timeout)) => {}
+                ret => panic!("{:?}", ret),
+            }
+
+            let flag = Arc::new(AtomicBool::new(false));
+            let setter = {
+                let flag = Arc::clone(&flag);
+                std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    flag.store(true, Ordering::Relaxed);
+                })
+            };
+            match run("+[>+<]", optimize, config.clone().interrupt(flag)) {
+                Err(VMError::Runtime(RuntimeError::Interrupted)) => {}
+                ret => panic!("{:?}", ret),
+            }
+            setter.join().unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::bfio::{input_from, output_to};
+
+    let src = "+++++[>+++++[>++<-]>[.-]<<-]";
+    for optimize in [false, true] {
+        let mut expected = vec![];
+        BfVM::from_source(
+            src,
+            input_from(""),
+            output_to(&mut expected),
+            optimize,
+            VmConfig::new(),
+        )
+        .and_then(|mut vm| vm.run())
+        .unwrap();
+
+        // pause every few jumps and carry on in a fresh VM on the other backend
+        let mut output = vec![];
+        let mut saved: Option<Vec<u8>> = None;
+        for backend in [Backend::Interp, Backend::Jit].into_iter().cycle() {
+            let mut out = vec![];
+            let config = VmConfig::new().backend(backend).budget(7);
+            let mut vm =
+                BfVM::from_source(src, input_from(""), output_to(&mut out), optimize, config)
+                    .unwrap();
+            if let Some(bytes) = &saved {
+                vm.restore(&Snapshot::from_bytes(bytes).unwrap()).unwrap();
+            }
+            let ret = vm.run();
+            saved = Some(vm.snapshot().to_bytes());
+            drop(vm);
+            output.extend(out);
+            match ret {
+                Ok(()) => break,
+                Err(VMError::Runtime(RuntimeError::Timeout)) => {}
+                ret => panic!("{:?}", ret),
+            }
+        }
+        assert_eq!(output, expected);
+
+        // or in the same VM, with a new budget
+        for backend in [Backend::Interp, Backend::Jit] {
+            let mut output = vec![];
+            let config = VmConfig::new().backend(backend).budget(30);
+            let mut vm = BfVM::from_source(
+                src,
+                input_from(""),
+                output_to(&mut output),
+                optimize,
+                config,
+            )
+            .unwrap();
+            assert!(matches!(
+                vm.run(),
+                Err(VMError::Runtime(RuntimeError::Timeout))
+            ));
+            assert_eq!(vm.engine.ir()[vm.pc()], BfIR::Jnz);
+            let other = BfVM::from_source(
+                "+[-]",
+                input_from(""),
+                Box::new(vec![]),
+                false,
+                VmConfig::new(),
+            )
+            .unwrap();
+            assert!(matches!(
+                vm.restore(&other.snapshot()),
+                Err(VMError::InvalidSnapshot(_))
+            ));
+            vm.set_budget(u64::MAX);
+            vm.run().unwrap();
+            assert_eq!(vm.pc(), 0);
+            drop(vm);
+            assert_eq!(output, expected);
+        }
+    }
+}
+
+/// Runs `src` with the input "ab", resuming with 3 more jumps whenever it runs
+/// out of budget. Returns the result, the state at every stop and the output.
+#[cfg(test)]
+fn run_stops(
+    src: &str,
+    optimize: bool,
+    config: VmConfig,
+) -> (std::result::Result<(), String>, Vec<Snapshot>, Vec<u8>) {
+    use crate::bfio::{input_from, output_to};
+
+    let mut output = vec![];
+    let mut stops = vec![];
+    let ret = BfVM::from_source(
+        src,
+        input_from("ab"),
+        output_to(&mut output),
+        optimize,
+        config,
+    )
+    .and_then(|mut vm| loop {
+        let ret = vm.run();
+        stops.push(vm.snapshot());
+        match ret {
+            Err(VMError::Runtime(RuntimeError::Timeout)) => vm.set_budget(3),
+            ret => return ret,
+        }
+    })
+    .map_err(|e| e.to_string());
+    (ret, stops, output)
+}
+
+/// Checks each of `srcs` on each of `configs`, with and without optimizing,
+/// against the interpreter. A block that runs off the tape stops the JIT
+/// before any of its ops, so the tape is left out after an error.
+#[cfg(test)]
+fn assert_like_interp(srcs: &[&str], configs: &[VmConfig]) {
+    let run = |src, optimize, config| {
+        let (ret, mut stops, output) = run_stops(src, optimize, config);
+        if ret.is_err() {
+            stops.pop();
+        }
+        (ret, stops, output)
+    };
+    for config in configs {
+        for &src in srcs {
+            for optimize in [false, true] {
+                let expected = run(src, optimize, config.clone().backend(Backend::Interp));
+                let actual = run(src, optimize, config.clone());
+                assert_eq!(actual, expected, "{} {:?} {}", src, config, optimize);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_guard_pages() {
+    use crate::bfio::input_from;
+    use crate::config::CellWidth;
+
+    let configs = [CellWidth::U8, CellWidth::U16, CellWidth::U32].map(|width| {
+        VmConfig::new()
+            .cell_width(width)
+            .tape_size(crate::tape::page_size() / width.bytes())
+            .eof(crate::config::EofPolicy::Zero)
+            .guard_pages(true)
+    });
+    assert_like_interp(
+        &[
+            "++++++++[>++++++++<-]>+.+.+.",
+            "<",
+            "+[>+]",
+            "+[<+]",
+            "+[>+.]",
+            ">+[-<+]",
+            "+[>>>+]",
+            "+[>+]<[-]+[<]<",
+            "+[>+]+[<]>[>]>",
+            "+[->>>+[<]>]",
+            ",[>,]",
+            "+[>>>>>>>>>>[-]<<<<<<<<<<<[>>>>>>>>>>>+<<<<<<<<<<<-]>+]",
+        ],
+        &configs,
+    );
+
+    assert!(matches!(
+        BfVM::from_source(
+            "",
+            input_from(""),
+            Box::new(vec![]),
+            false,
+            VmConfig::new().tape_size(100).guard_pages(true)
+        ),
+        Err(VMError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_register_cell() {
+    use crate::config::CellWidth;
+
+    let configs = [CellWidth::U8, CellWidth::U16, CellWidth::U32].map(|width| {
+        VmConfig::new()
+            .cell_width(width)
+            .tape_size(64)
+            .budget(3)
+            .register_cell(true)
+    });
+    assert_like_interp(
+        &[
+            // changed, then written, read or tested while still in the register
+            "-.--.+++>-<-.",
+            "+.,+.,+.-.",
+            ",+[-.+.-]++.",
+            "+>+<-+[->++<]>.",
+            "+++[>+++[>+<-]<-]>>.<<-----",
+            // changed, then running off the tape
+            "+++<",
+            "+>++<--->>>-<<<<",
+            "+[>+]",
+            // changed, then paused at the `]` a budget stops at
+            "++++++[-]+",
+            "+++[->+<]>[<]",
+            "++[>+++[>+<-]+<-]>>>.",
+        ],
+        &configs,
+    );
+
+    // the cell is in memory when the next block runs off the tape
+    for src in ["+++.<", "+++.+[<]", "-.,-[>++.]"] {
+        for config in &configs {
+            let expected = run_stops(src, false, config.clone().backend(Backend::Interp));
+            assert!(expected.0.is_err(), "{}", src);
+            assert_eq!(run_stops(src, false, config.clone()), expected, "{}", src);
+        }
     }
 }
+
+#[test]
+fn test_prefix() {
+    use crate::bfio::{input_from, output_to};
+
+    // a banner longer than the output buffer, then an echo
+    let src = "+".repeat(65) + &".".repeat(5000) + ",.";
+    for backend in [Backend::Interp, Backend::Jit] {
+        let mut outputs = vec![];
+        // with a budget, the whole program runs every time
+        for config in [VmConfig::new(), VmConfig::new().budget(u64::MAX)] {
+            let mut output = vec![];
+            let mut vm = BfVM::from_source(
+                &src,
+                input_from("x"),
+                output_to(&mut output),
+                false,
+                config.backend(backend),
+            )
+            .unwrap();
+            // the second run starts on the tape the first one left
+            vm.run().unwrap();
+            vm.run().unwrap();
+            drop(vm);
+            outputs.push(output);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0].len(), 10002);
+        assert_eq!(outputs[0][5001..], [b'x' + 65; 5001]);
+    }
+}
+
+#[test]
+fn test_rerun_optimized() {
+    use crate::bfio::output_to;
+
+    // later runs start on the tape the first one left, which the optimizer
+    // must not take for blank
+    for backend in [Backend::Interp, Backend::Jit] {
+        for (src, printed, tape) in [
+            ("[.[-]]+", &[1, 1][..], [1, 0, 0, 0]),
+            ("[>]+", &[], [1, 1, 1, 0]),
+        ] {
+            for optimize in [false, true] {
+                let mut output = vec![];
+                let mut vm = BfVM::from_source(
+                    src,
+                    Box::new(std::io::empty()),
+                    output_to(&mut output),
+                    optimize,
+                    VmConfig::new().tape_size(4).backend(backend),
+                )
+                .unwrap();
+                for _ in 0..3 {
+                    vm.run().unwrap();
+                }
+                assert_eq!(vm.tape(), tape, "{} {:?} {}", src, backend, optimize);
+                drop(vm);
+                assert_eq!(output, printed, "{} {:?} {}", src, backend, optimize);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_disassemble() {
+    let vm = |backend| {
+        BfVM::from_source(
+            "+.\n,+",
+            Box::new(std::io::empty()),
+            Box::new(std::io::sink()),
+            false,
+            VmConfig::new().backend(backend),
+        )
+        .unwrap()
+    };
+    assert_eq!(vm(Backend::Interp).disassemble(), None);
+
+    let asm = vm(Backend::Jit).disassemble().unwrap();
+    let lines: Vec<_> = asm.lines().collect();
+    let op = lines
+        .iter()
+        .position(|&l| l == "; 3 2:2  p[0] += 1")
+        .unwrap();
+    assert!(lines[op + 1].ends_with("add byte [rcx],1"));
+    assert!(lines.contains(&"; epilogue"));
+    // what the prefix prints is data, not code
+    assert!(lines.last().unwrap().ends_with("db 01h"));
+}
@@ -0,0 +1,765 @@
+use crate::analysis::{self, PtrRange};
+use crate::bfir::BfIR;
+use crate::bfjit::{OutputBuffer, OUTPUT_BUFFER_SIZE};
+use crate::config::{CellWidth, VmConfig};
+use crate::peval::{self, Prefix};
+
+use dynasm::dynasm;
+use dynasmrt::x64::{self, X64Relocation};
+use dynasmrt::{DynamicLabel, DynasmApi, DynasmLabelApi, VecAssembler};
+
+/// An x86-64 assembler the lowering can emit into.
+pub(crate) trait Asm: DynasmApi + DynasmLabelApi<Relocation = X64Relocation> {
+    fn new_dynamic_label(&mut self) -> DynamicLabel;
+}
+
+impl Asm for x64::Assembler {
+    fn new_dynamic_label(&mut self) -> DynamicLabel {
+        x64::Assembler::new_dynamic_label(self)
+    }
+}
+
+impl Asm for VecAssembler<X64Relocation> {
+    fn new_dynamic_label(&mut self) -> DynamicLabel {
+        VecAssembler::new_dynamic_label(self)
+    }
+}
+
+/// The environment compiled code runs in. The lowering keeps its state in
+/// fixed registers, which the prologue sets up:
+///
+/// - `rbx`: the `OutputBuffer`
+/// - `r13`, `r14`: start and end of the tape
+/// - `rcx`: the pointer
+/// - `r8`: the cell under the pointer, within straight-line code when
+///   [`VmConfig::register_cell`] is on
+///
+/// `get_byte` and `flush` may clobber `rcx`, `r15` and any caller-saved
+/// register. The epilogue defines the `->overflow` and `->io_error` labels.
+/// Failed bounds checks `call ->overflow`, so the return address on the
+/// stack locates the check, and `rcx` holds the pointer at that point. A
+/// fault on a guard page must reach `->overflow` the same way.
+pub(crate) trait Runtime {
+    fn prologue<D: Asm>(&self, ops: &mut D);
+
+    /// Reads a byte into the cell at `rsi`.
+    fn get_byte<D: Asm>(&self, ops: &mut D);
+
+    /// Writes out and empties the output buffer. Runs before each read.
+    fn flush<D: Asm>(&self, ops: &mut D);
+
+    /// Runs at each `]` before it branches, and may clobber `rax`, `rdx`
+    /// and the flags.
+    fn back_edge<D: Asm>(&self, _ops: &mut D) {}
+
+    /// Bytes of inaccessible memory on each side of the tape. Ops that reach
+    /// less far than this leave out their bounds checks and fault instead.
+    fn guard(&self) -> usize {
+        0
+    }
+
+    fn epilogue<D: Asm>(&self, ops: &mut D);
+}
+
+/// Lowers `code` to machine code running on `rt` with the tape `config`
+/// describes. Returns the offset at which the code for each op starts,
+/// followed by the offsets of the epilogue and of the data after it.
+///
+/// Checks that [`analysis::ptr_ranges`] proves can never fail are left
+/// out, so the code must only be entered at an op with the pointer in its
+/// range.
+///
+/// Entered at the top, the code first sets up the tape and output that
+/// [`peval::eval_prefix`] computed, and goes on from where that stopped, so
+/// it must then run on a blank tape.
+pub(crate) fn compile<D: Asm, R: Runtime>(
+    ops: &mut D,
+    rt: &R,
+    code: &[BfIR],
+    config: &VmConfig,
+) -> Vec<usize> {
+    let mut loops = vec![];
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+
+    let width = config.cell_width;
+    let len = config.tape_size;
+    let ranges = analysis::ptr_ranges(code, config.tape_origin, len);
+
+    rt.prologue(ops);
+
+    let prefix = peval::eval_prefix(code, config);
+    let mut data = vec![];
+    let resume = prefix.as_ref().map(|prefix| {
+        let label = ops.new_dynamic_label();
+        data = compile_prefix(ops, rt, width, prefix, label);
+        (prefix.pc, label)
+    });
+
+    // Straight-line blocks check their whole extent once on entry, so the
+    // ops inside them can move and address the pointer freely.
+    let mut checked = false;
+    let guard = rt.guard() as u64;
+
+    // Whether `r8` holds a newer value of the cell under the pointer than
+    // memory does. It is stored back ahead of the next op that is not an
+    // `AddVal` or `SubVal`, so every op starts with the tape up to date.
+    let mut cached = false;
+
+    use BfIR::*;
+    for (i, &ir) in code.iter().enumerate() {
+        if cached && !matches!(ir, AddVal { .. } | SubVal { .. }) {
+            compile_store_r8(ops, width);
+            cached = false;
+        }
+        offsets.push(ops.offset().0);
+        if let Some((_, label)) = resume.filter(|&(pc, _)| pc == i) {
+            dynasm!(ops
+                ; => label
+            );
+        }
+
+        let block_op = matches!(
+            ir,
+            AddPtr(_) | SubPtr(_) | AddVal { .. } | SubVal { .. } | GetByte { .. } | PutByte { .. }
+        );
+        if block_op && !checked {
+            compile_block_check(ops, width, &code[i..], ranges[i], len, guard);
+            checked = true;
+        }
+        if !block_op || matches!(ir, GetByte { .. } | PutByte { .. }) {
+            checked = false;
+        }
+
+        match ir {
+            AddPtr(x) => compile_move(ops, scale(x as i64, width)),
+            SubPtr(x) => compile_move(ops, -scale(x as i64, width)),
+            AddVal { offset: 0, val } | SubVal { offset: 0, val } if config.register_cell => {
+                if !cached {
+                    compile_load_r8(ops, width);
+                    cached = true;
+                }
+                let val = match ir {
+                    SubVal { .. } => val.wrapping_neg(),
+                    _ => val,
+                };
+                compile_add_r8(ops, width, val)
+            }
+            AddVal { offset, val } => compile_add_cell(ops, width, disp(offset, width), val),
+            SubVal { offset, val } => {
+                compile_add_cell(ops, width, disp(offset, width), val.wrapping_neg())
+            }
+            GetByte { offset } => {
+                dynasm!(ops
+                    ; mov r15, rcx          // save ptr
+                );
+                // an interactive program may have just printed a prompt
+                rt.flush(ops);
+                dynasm!(ops
+                    ; mov rcx, r15          // recover ptr
+                    ; lea rsi, [rcx + disp(offset, width)]
+                );
+                rt.get_byte(ops);
+                dynasm!(ops
+                    ; mov rcx, r15          // recover ptr
+                )
+            }
+            PutByte { offset } => {
+                dynasm!(ops
+                    ; mov  rax, QWORD [rbx]     // len
+                    ; mov  dl, BYTE [rcx + disp(offset, width)]  // low byte of the cell
+                    ; mov  BYTE [rbx + rax + OUTPUT_DATA], dl    // data[len] = dl
+                    ; inc  rax
+                    ; mov  QWORD [rbx], rax     // len += 1
+                    ; cmp  rax, OUTPUT_BUFFER_SIZE as i32
+                    ; jb   >next                // jmp if the buffer has room left
+                    ; mov  r15, rcx             // save ptr
+                );
+                rt.flush(ops);
+                dynasm!(ops
+                    ; mov  rcx, r15             // recover ptr
+                    ; next:
+                )
+            }
+            SetZero => compile_set_cell(ops, width, 0, 0),
+            SetValue(val) => compile_set_cell(ops, width, 0, val),
+            MulAdd { offset, factor } => {
+                compile_load_cell(ops, width);
+                dynasm!(ops
+                    ; test eax, eax
+                    ; jz >skip              // nothing to add if *ptr == 0
+                );
+                let target = scale(offset as i64, width);
+                let safe = ranges[i].is_none_or(|r| r.covers(offset as i64, offset as i64, len));
+                match i32::try_from(target) {
+                    Ok(target) if safe || target.unsigned_abs() < guard as u32 => dynasm!(ops
+                        ; lea rdx, [rcx + target]
+                    ),
+                    Ok(target) if target < 0 => dynasm!(ops
+                        ; lea rdx, [rcx + target]
+                        ; cmp rdx, r13      // target - memory_start
+                        ; jnb >ok
+                        ; call ->overflow    // if target < memory_start
+                        ; ok:
+                    ),
+                    Ok(target) => dynasm!(ops
+                        ; lea rdx, [rcx + target]
+                        ; cmp rdx, r14      // target - memory_end
+                        ; jb  >ok
+                        ; call ->overflow    // if target >= memory_end
+                        ; ok:
+                    ),
+                    Err(_) => dynasm!(ops
+                        ; call ->overflow
+                    ),
+                }
+                dynasm!(ops
+                    ; imul eax, eax, factor as i32
+                );
+                compile_add_cell_eax(ops, width); // *target += *ptr * factor
+                dynasm!(ops
+                    ; skip:
+                )
+            }
+            ScanRight(1) => {
+                dynasm!(ops
+                    ; pxor xmm1, xmm1
+                    ; vector:
+                    ; lea rax, [rcx + 16]
+                    ; cmp rax, r14
+                    ; ja >scalar            // fewer than 16 bytes left
+                    ; movdqu xmm0, [rcx]
+                );
+                compile_pcmpeq(ops, width);
+                dynasm!(ops
+                    ; pmovmskb eax, xmm0    // bits set where cells are zero
+                    ; test eax, eax
+                    ; jnz >found
+                    ; add rcx, 16
+                    ; jmp <vector
+                    ; found:
+                    ; bsf eax, eax
+                    ; add rcx, rax          // ptr = first zero cell
+                    ; jmp >done
+                    ; scalar:
+                );
+                if guard == 0 {
+                    dynasm!(ops
+                        ; cmp rcx, r14
+                        ; jb  >ok
+                        ; call ->overflow    // if ptr >= memory_end
+                        ; ok:
+                    );
+                }
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; je >done
+                    ; add rcx, width.bytes() as i32
+                    ; jmp <scalar
+                    ; done:
+                )
+            }
+            ScanLeft(1) => {
+                let back = 16 - width.bytes() as i32;
+                dynasm!(ops
+                    ; pxor xmm1, xmm1
+                    ; vector:
+                    ; lea rax, [rcx - back]
+                    ; cmp rax, r13
+                    ; jb >scalar            // fewer than 16 bytes left
+                    ; movdqu xmm0, [rax]
+                );
+                compile_pcmpeq(ops, width);
+                dynasm!(ops
+                    ; pmovmskb edx, xmm0    // bits set where cells are zero
+                    ; test edx, edx
+                    ; jnz >found
+                    ; sub rcx, 16
+                    ; jmp <vector
+                    ; found:
+                    ; bsr edx, edx          // last byte of the last zero cell
+                    ; lea rcx, [rax + rdx - (width.bytes() as i32 - 1)]
+                    ; jmp >done
+                    ; scalar:
+                );
+                if guard == 0 {
+                    dynasm!(ops
+                        ; cmp rcx, r13
+                        ; jnb >ok
+                        ; call ->overflow    // if ptr < memory_start
+                        ; ok:
+                    );
+                }
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; je >done
+                    ; sub rcx, width.bytes() as i32
+                    ; jmp <scalar
+                    ; done:
+                )
+            }
+            ScanRight(x) => {
+                dynasm!(ops
+                    ; scan:
+                );
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; je >done
+                );
+                // the next round's compare faults behind guard pages
+                let stride = scale(x as i64, width);
+                compile_move(ops, stride);
+                if stride as u64 >= guard {
+                    dynasm!(ops
+                        ; cmp rcx, r14
+                        ; jb  >ok
+                        ; call ->overflow    // if ptr >= memory_end
+                        ; ok:
+                    );
+                }
+                dynasm!(ops
+                    ; jmp <scan
+                    ; done:
+                )
+            }
+            ScanLeft(x) => {
+                dynasm!(ops
+                    ; scan:
+                );
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; je >done
+                );
+                let stride = scale(x as i64, width);
+                compile_move(ops, -stride);
+                if stride as u64 >= guard {
+                    dynasm!(ops
+                        ; cmp rcx, r13
+                        ; jnb >ok
+                        ; call ->overflow    // if ptr < memory_start
+                        ; ok:
+                    );
+                }
+                dynasm!(ops
+                    ; jmp <scan
+                    ; done:
+                )
+            }
+            Jz => {
+                let left = ops.new_dynamic_label();
+                let right = ops.new_dynamic_label();
+                loops.push((left, right));
+
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; jz => right       // jmp if *ptr == 0
+                    ; => left
+                )
+            }
+            Jnz => {
+                let (left, right) = loops.pop().unwrap();
+                rt.back_edge(ops);
+                compile_cmp_cell_zero(ops, width);
+                dynasm!(ops
+                    ; jnz => left       // jmp if *ptr != 0
+                    ; => right
+                )
+            }
+        }
+    }
+
+    if cached {
+        compile_store_r8(ops, width);
+    }
+    offsets.push(ops.offset().0);
+    if let Some((_, label)) = resume.filter(|&(pc, _)| pc == code.len()) {
+        dynasm!(ops
+            ; => label
+        );
+    }
+    rt.epilogue(ops);
+
+    // the prefix's output goes after all the code
+    offsets.push(ops.offset().0);
+    for (label, chunk) in data {
+        dynasm!(ops
+            ; => label
+            ; .bytes chunk
+        );
+    }
+
+    offsets
+}
+
+pub(crate) const OUTPUT_DATA: i32 = std::mem::offset_of!(OutputBuffer, data) as i32;
+
+/// Converts a distance in cells to a distance in bytes.
+fn scale(cells: i64, width: CellWidth) -> i64 {
+    cells * width.bytes() as i64
+}
+
+/// Byte displacement of the cell at `offset`. Offsets too large for a
+/// displacement are only reachable behind a failed block check, so
+/// saturating them is harmless.
+fn disp(offset: i32, width: CellWidth) -> i32 {
+    offset.saturating_mul(width.bytes() as i32)
+}
+
+/// Stores the cells and prints the output of `prefix`, then jumps to
+/// `resume` with the pointer where the prefix left it. Returns the chunks of
+/// output the code copies, each to be placed at its label.
+fn compile_prefix<'a, D: Asm, R: Runtime>(
+    ops: &mut D,
+    rt: &R,
+    width: CellWidth,
+    prefix: &'a Prefix,
+    resume: DynamicLabel,
+) -> Vec<(DynamicLabel, &'a [u8])> {
+    for &(cell, val) in &prefix.cells {
+        compile_point_at(ops, scale(cell as i64, width));
+        compile_set_cell(ops, width, 0, val);
+    }
+
+    let mut data = vec![];
+    for chunk in prefix.output.chunks(OUTPUT_BUFFER_SIZE) {
+        let label = ops.new_dynamic_label();
+        data.push((label, chunk));
+        dynasm!(ops
+            ; lea  rsi, [=> label]
+            ; lea  rdi, [rbx + OUTPUT_DATA]
+            ; mov  ecx, chunk.len() as i32
+            ; rep  movsb                // the buffer starts out empty
+            ; mov  QWORD [rbx], chunk.len() as i32
+        );
+        if chunk.len() == OUTPUT_BUFFER_SIZE {
+            rt.flush(ops);
+        }
+    }
+
+    compile_point_at(ops, scale(prefix.ptr as i64, width));
+    dynasm!(ops
+        ; jmp => resume
+    );
+    data
+}
+
+/// `ptr = memory_start + bytes`
+fn compile_point_at<D: Asm>(ops: &mut D, bytes: i64) {
+    match i32::try_from(bytes) {
+        Ok(bytes) => dynasm!(ops
+            ; lea rcx, [r13 + bytes]
+        ),
+        Err(_) => dynasm!(ops
+            ; mov rcx, QWORD bytes
+            ; add rcx, r13
+        ),
+    }
+}
+
+/// `ptr += bytes`
+fn compile_move<D: Asm>(ops: &mut D, bytes: i64) {
+    match i32::try_from(bytes) {
+        Ok(bytes) => dynasm!(ops
+            ; add rcx, bytes
+        ),
+        Err(_) => dynasm!(ops
+            ; mov rax, QWORD bytes
+            ; add rcx, rax
+        ),
+    }
+}
+
+/// `*(ptr + disp) += val`
+fn compile_add_cell<D: Asm>(ops: &mut D, width: CellWidth, disp: i32, val: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; add BYTE [rcx + disp], val as i8
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; add WORD [rcx + disp], val as i16
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; add DWORD [rcx + disp], val as i32
+        ),
+    }
+}
+
+/// `*(ptr + disp) = val`
+fn compile_set_cell<D: Asm>(ops: &mut D, width: CellWidth, disp: i32, val: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; mov BYTE [rcx + disp], val as i8
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; mov WORD [rcx + disp], val as i16
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; mov DWORD [rcx + disp], val as i32
+        ),
+    }
+}
+
+/// `r8 = *ptr`
+fn compile_load_r8<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; movzx r8d, BYTE [rcx]
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; movzx r8d, WORD [rcx]
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; mov r8d, DWORD [rcx]
+        ),
+    }
+}
+
+/// `r8 += val`, wrapping at the cell width
+fn compile_add_r8<D: Asm>(ops: &mut D, width: CellWidth, val: u32) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; add r8b, val as i8
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; add r8w, val as i16
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; add r8d, val as i32
+        ),
+    }
+}
+
+/// `*ptr = r8`
+fn compile_store_r8<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; mov BYTE [rcx], r8b
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; mov WORD [rcx], r8w
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; mov DWORD [rcx], r8d
+        ),
+    }
+}
+
+/// `eax = *ptr`
+fn compile_load_cell<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; movzx eax, BYTE [rcx]
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; movzx eax, WORD [rcx]
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; mov eax, DWORD [rcx]
+        ),
+    }
+}
+
+/// `*rdx += eax`
+fn compile_add_cell_eax<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; add BYTE [rdx], al
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; add WORD [rdx], ax
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; add DWORD [rdx], eax
+        ),
+    }
+}
+
+/// `*ptr - 0`
+fn compile_cmp_cell_zero<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; cmp BYTE [rcx], 0
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; cmp WORD [rcx], 0
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; cmp DWORD [rcx], 0
+        ),
+    }
+}
+
+/// Compares the cells in `xmm0` with the zeros in `xmm1`.
+fn compile_pcmpeq<D: Asm>(ops: &mut D, width: CellWidth) {
+    match width {
+        CellWidth::U8 => dynasm!(ops
+            ; pcmpeqb xmm0, xmm1
+        ),
+        CellWidth::U16 => dynasm!(ops
+            ; pcmpeqw xmm0, xmm1
+        ),
+        CellWidth::U32 => dynasm!(ops
+            ; pcmpeqd xmm0, xmm1
+        ),
+    }
+}
+
+/// Emits a range check covering every cell the block at the head of
+/// `code` touches, leaving out either side that `range` already keeps on a
+/// tape of `len` cells. Within `guard` bytes of the tape, reading the
+/// farthest cell is check enough, as it faults on a guard page.
+fn compile_block_check<D: Asm>(
+    ops: &mut D,
+    width: CellWidth,
+    code: &[BfIR],
+    range: Option<PtrRange>,
+    len: usize,
+    guard: u64,
+) {
+    let (lo, hi) = block_extent(code);
+    // a block that never runs needs no check
+    let (safe_lo, safe_hi) = match range {
+        Some(r) => (r.covers(lo, 0, len), r.covers(0, hi, len)),
+        None => (true, true),
+    };
+    let (lo, hi) = (scale(lo, width), scale(hi, width));
+
+    if lo < 0 && !safe_lo {
+        match i32::try_from(lo) {
+            Ok(lo) if lo.unsigned_abs() as u64 <= guard => dynasm!(ops
+                ; cmp BYTE [rcx + lo], 0
+            ),
+            Ok(lo) => dynasm!(ops
+                ; lea rax, [rcx + lo]
+                ; cmp rax, r13          // (ptr + lo) - memory_start
+                ; jnb >ok
+                ; call ->overflow        // if ptr + lo < memory_start
+                ; ok:
+            ),
+            Err(_) => dynasm!(ops
+                ; call ->overflow
+            ),
+        }
+    }
+    if hi > 0 && !safe_hi {
+        match i32::try_from(hi) {
+            Ok(hi) if (hi as u64) < guard => dynasm!(ops
+                ; cmp BYTE [rcx + hi], 0
+            ),
+            Ok(hi) => dynasm!(ops
+                ; lea rax, [rcx + hi]
+                ; cmp rax, r14          // (ptr + hi) - memory_end
+                ; jb  >ok
+                ; call ->overflow        // if ptr + hi >= memory_end
+                ; ok:
+            ),
+            Err(_) => dynasm!(ops
+                ; call ->overflow
+            ),
+        }
+    }
+}
+
+/// Farthest from the pointer, in cells, that a block or any other single op
+/// reaches. Guard pages wider than this stand in for every bounds check.
+pub(crate) fn max_reach(code: &[BfIR]) -> u64 {
+    let mut max = 0;
+    let mut in_block = false;
+
+    use BfIR::*;
+    for (i, &ir) in code.iter().enumerate() {
+        let reach = match ir {
+            AddPtr(_) | SubPtr(_) | AddVal { .. } | SubVal { .. } if in_block => 0,
+            AddPtr(_)
+            | SubPtr(_)
+            | AddVal { .. }
+            | SubVal { .. }
+            | GetByte { .. }
+            | PutByte { .. } => {
+                let (lo, hi) = block_extent(&code[i..]);
+                lo.unsigned_abs().max(hi.unsigned_abs())
+            }
+            MulAdd { offset, .. } => offset.unsigned_abs() as u64,
+            ScanRight(x) | ScanLeft(x) => x as u64,
+            Jz | Jnz | SetZero | SetValue(_) => 0,
+        };
+        in_block = matches!(ir, AddPtr(_) | SubPtr(_) | AddVal { .. } | SubVal { .. });
+        max = max.max(reach);
+    }
+    max
+}
+
+/// Returns the lowest and highest offsets from the current pointer that the
+/// straight-line block at the head of `code` moves to or accesses.
+///
+/// A block ends after its first I/O op, so a failed check never discards
+/// output the program would have produced before running off the tape.
+fn block_extent(code: &[BfIR]) -> (i64, i64) {
+    let (mut lo, mut hi) = (0_i64, 0_i64);
+    let mut cur = 0_i64;
+
+    use BfIR::*;
+    for &ir in code {
+        let pos = match ir {
+            AddPtr(x) => {
+                cur += x as i64;
+                cur
+            }
+            SubPtr(x) => {
+                cur -= x as i64;
+                cur
+            }
+            AddVal { offset, .. } | SubVal { offset, .. } => cur + offset as i64,
+            GetByte { offset } | PutByte { offset } => {
+                let pos = cur + offset as i64;
+                lo = lo.min(pos);
+                hi = hi.max(pos);
+                break;
+            }
+            _ => break,
+        };
+        lo = lo.min(pos);
+        hi = hi.max(pos);
+    }
+
+    (lo, hi)
+}
+
+/// Finds the op that ran off a tape of `len` cells after a check in the code
+/// for `code[0]` failed with the pointer on `cell`. Returns its index in
+/// `code` and the cell it tried to reach.
+pub(crate) fn overflow_site(code: &[BfIR], cell: i64, len: usize) -> (usize, i64) {
+    use BfIR::*;
+    match code.first() {
+        Some(&MulAdd { offset, .. }) => return (0, cell + offset as i64),
+        Some(ScanRight(_) | ScanLeft(_)) => return (0, cell),
+        _ => {}
+    }
+
+    // a block check failed before the block ran, so replay it
+    let mut cur = cell;
+    for (i, &ir) in code.iter().enumerate() {
+        let pos = match ir {
+            AddPtr(x) => {
+                cur += x as i64;
+                cur
+            }
+            SubPtr(x) => {
+                cur -= x as i64;
+                cur
+            }
+            AddVal { offset, .. }
+            | SubVal { offset, .. }
+            | GetByte { offset }
+            | PutByte { offset } => cur + offset as i64,
+            _ => break,
+        };
+        if !(0..len as i64).contains(&pos) {
+            return (i, pos);
+        }
+        if matches!(ir, GetByte { .. } | PutByte { .. }) {
+            break;
+        }
+    }
+
+    (0, cell)
+}
@@ -0,0 +1,277 @@
+use crate::bfjit::Backend;
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+pub const DEFAULT_TAPE_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CellWidth {
+    #[default]
+    #[value(name = "8")]
+    U8,
+    #[value(name = "16")]
+    U16,
+    #[value(name = "32")]
+    U32,
+}
+
+impl CellWidth {
+    /// Size of one cell in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+        }
+    }
+}
+
+/// What `,` stores when the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum EofPolicy {
+    /// Leave the cell as it is
+    #[default]
+    Unchanged,
+    /// Store 0
+    Zero,
+    /// Store -1, i.e. every bit of the cell set
+    MinusOne,
+}
+
+/// Execution settings for a [`BfVM`](crate::BfVM).
+///
+/// ```
+/// use bfrs::config::{CellWidth, VmConfig};
+///
+/// let config = VmConfig::new()
+///     .tape_size(64 * 1024)
+///     .cell_width(CellWidth::U16)
+///     .tape_origin(1024);
+/// ```
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub(crate) backend: Backend,
+    pub(crate) tape_size: usize,
+    pub(crate) cell_width: CellWidth,
+    pub(crate) tape_origin: usize,
+    pub(crate) eof: EofPolicy,
+    pub(crate) budget: Option<u64>,
+    pub(crate) interrupt: Option<Arc<AtomicBool>>,
+    pub(crate) guard_pages: bool,
+    pub(crate) register_cell: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::default(),
+            tape_origin: 0,
+            eof: EofPolicy::default(),
+            budget: None,
+            interrupt: None,
+            guard_pages: false,
+            register_cell: false,
+        }
+    }
+}
+
+impl VmConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Number of cells on the tape.
+    pub fn tape_size(mut self, cells: usize) -> Self {
+        self.tape_size = cells;
+        self
+    }
+
+    pub fn cell_width(mut self, width: CellWidth) -> Self {
+        self.cell_width = width;
+        self
+    }
+
+    /// Cell the pointer starts at, leaving `cell` cells to its left.
+    pub fn tape_origin(mut self, cell: usize) -> Self {
+        self.tape_origin = cell;
+        self
+    }
+
+    pub fn eof(mut self, policy: EofPolicy) -> Self {
+        self.eof = policy;
+        self
+    }
+
+    /// Number of `]` the program may run before it fails with
+    /// [`RuntimeError::Timeout`](crate::error::RuntimeError::Timeout). The
+    /// budget is shared by every run of the VM; see [`BfVM::set_budget`](crate::BfVM::set_budget).
+    pub fn budget(mut self, jumps: u64) -> Self {
+        self.budget = Some(jumps);
+        self
+    }
+
+    /// A flag that, once set from any thread, makes the program fail with
+    /// [`RuntimeError::Interrupted`](crate::error::RuntimeError::Interrupted)
+    /// at its next `]`.
+    pub fn interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    /// Puts the tape between inaccessible guard pages, so the JIT can leave
+    /// out most bounds checks and let the fault report the overflow instead.
+    /// The tape must fill whole pages.
+    pub fn guard_pages(mut self, on: bool) -> Self {
+        self.guard_pages = on;
+        self
+    }
+
+    /// Makes compiled code keep the cell under the pointer in a register
+    /// across straight-line arithmetic, and store it back only before the
+    /// pointer moves, I/O and loop tests.
+    pub fn register_cell(mut self, on: bool) -> Self {
+        self.register_cell = on;
+        self
+    }
+
+    /// Size of the tape in bytes.
+    pub(crate) fn tape_bytes(&self) -> Option<usize> {
+        self.tape_size.checked_mul(self.cell_width.bytes())
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        if self.tape_size == 0 {
+            return Err("tape size must be non-zero");
+        }
+        if self.tape_origin >= self.tape_size {
+            return Err("tape origin must lie on the tape");
+        }
+        match self.tape_bytes() {
+            None => return Err("tape is too large"),
+            Some(bytes) if self.guard_pages && bytes % crate::tape::page_size() != 0 => {
+                return Err("guard pages need a tape of whole pages")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config() {
+    use crate::bfio::{input_from, output_to};
+    use crate::bfjit::BfVM;
+    use crate::error::{RuntimeError, VMError};
+
+    fn run(src: &str, config: VmConfig) -> Vec<std::result::Result<Vec<u8>, VMError>> {
+        let mut rets = vec![];
+        for backend in [Backend::Interp, Backend::Jit] {
+            for optimize in [false, true] {
+                let mut output = vec![];
+                let config = config.clone().backend(backend);
+                let ret = BfVM::from_source(
+                    src,
+                    input_from(""),
+                    output_to(&mut output),
+                    optimize,
+                    config,
+                )
+                .and_then(|mut vm| vm.run());
+                rets.push(ret.map(|()| output));
+            }
+        }
+        rets
+    }
+
+    fn expect(src: &str, config: VmConfig, expected: &[u8]) {
+        for ret in run(src, config) {
+            assert_eq!(ret.unwrap(), expected, "{}", src);
+        }
+    }
+
+    fn expect_overflow(src: &str, config: VmConfig) {
+        for ret in run(src, config) {
+            match ret {
+                Err(VMError::Runtime(RuntimeError::PointerOverflow { .. })) => {}
+                _ => panic!("{}", src),
+            }
+        }
+    }
+
+    // 16^4 = 65536 only survives in 32-bit cells, 16^2 = 256 in 16- and 32-bit ones
+    let pow = "++++++++++++++++[>++++++++++++++++<-]>[<++++++++++++++++>-]<";
+    let test = format!("[>+<[-]]>{}.", "+".repeat(64));
+    let src16 = format!("{}{}", pow, test);
+    let src32 = format!(
+        "{}[>++++++++++++++++<-]>[<++++++++++++++++>-]<{}",
+        pow, test
+    );
+    expect(&src16, VmConfig::new(), b"@");
+    expect(&src16, VmConfig::new().cell_width(CellWidth::U16), b"A");
+    expect(&src32, VmConfig::new().cell_width(CellWidth::U16), b"@");
+    expect(&src32, VmConfig::new().cell_width(CellWidth::U32), b"A");
+
+    let left = format!("<{}.", "+".repeat(65));
+    expect_overflow(&left, VmConfig::new());
+    expect(&left, VmConfig::new().tape_origin(1), b"A");
+
+    let fill = format!("{}+{}[>]", "+>".repeat(39), "<".repeat(39));
+    for width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        let config = VmConfig::new().cell_width(width);
+        expect(&fill, config.clone().tape_size(41), b"");
+        expect_overflow(&fill, config.tape_size(40));
+    }
+
+    for config in [
+        VmConfig::new().tape_size(0),
+        VmConfig::new().tape_origin(DEFAULT_TAPE_SIZE),
+    ] {
+        match run("", config).remove(0) {
+            Err(VMError::InvalidConfig(_)) => {}
+            _ => panic!(),
+        }
+    }
+}
+
+#[test]
+fn test_eof() {
+    use crate::bfio::{input_from, output_to};
+    use crate::bfjit::BfVM;
+
+    let cases: [(_, _, &[u8]); 5] = [
+        (EofPolicy::Unchanged, CellWidth::U8, b"!!\""),
+        (EofPolicy::Zero, CellWidth::U8, b"!\0\x01"),
+        (EofPolicy::MinusOne, CellWidth::U8, b"!\xff"),
+        (EofPolicy::MinusOne, CellWidth::U16, b"!\xff"),
+        (EofPolicy::MinusOne, CellWidth::U32, b"!\xff"),
+    ];
+    // reads past the end of the input, then prints the cell plus one unless
+    // it wrapped to zero
+    let src = ",.,.+[.[-]]";
+    for (eof, width, expected) in cases {
+        for backend in [Backend::Interp, Backend::Jit] {
+            for optimize in [false, true] {
+                let mut output = vec![];
+                let config = VmConfig::new().backend(backend).cell_width(width).eof(eof);
+                BfVM::from_source(
+                    src,
+                    input_from("!"),
+                    output_to(&mut output),
+                    optimize,
+                    config,
+                )
+                .and_then(|mut vm| vm.run())
+                .unwrap();
+                assert_eq!(output, expected, "{:?} {:?}", eof, width);
+            }
+        }
+    }
+}
@@ -1,10 +1,31 @@
+use crate::bfir::Position;
+
 #[derive(Debug, thiserror::Error)]
 pub enum RuntimeError {
     #[error("IO: {0}")]
     IO(#[from] std::io::Error),
 
-    #[error("Pointer overflow")]
-    PointerOverflow,
+    /// `ptr` is the cell the op tried to reach, and `pos` the source
+    /// position of the op, if the program came with positions.
+    #[error("Pointer overflow{} (ptr = {ptr})", at(.pos))]
+    PointerOverflow { ptr: i64, pos: Option<Position> },
+
+    #[error("Budget exhausted")]
+    Timeout,
+
+    #[error("Interrupted")]
+    Interrupted,
+
+    /// The input would block. Running the VM again retries the `,`.
+    #[error("Waiting for input")]
+    NeedInput,
+}
+
+fn at(pos: &Option<Position>) -> String {
+    match pos {
+        Some(pos) => format!(" at {}", pos),
+        None => String::new(),
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -15,8 +36,17 @@ pub enum VMError {
     #[error("Compile: {0}")]
     Compile(#[from] crate::bfir::CompileError),
 
+    #[error("Invalid config: {0}")]
+    InvalidConfig(&'static str),
+
+    #[error("Unbalanced loop in IR at op {0}")]
+    UnbalancedLoop(usize),
+
     #[error("Runtime: {0}")]
     Runtime(#[from] RuntimeError),
+
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(&'static str),
 }
 
 pub type Result<T> = std::result::Result<T, VMError>;
@@ -0,0 +1,174 @@
+use crate::bfir::{self, CompileError, Token};
+
+/// Columns [`format`] wraps runs of commands at by default.
+pub const DEFAULT_WIDTH: usize = 80;
+
+const INDENT: usize = 4;
+
+/// Lays `src` out one loop bracket per line, with loop bodies indented by
+/// depth and the runs of commands between them wrapped at `width` columns.
+///
+/// Comment lines are kept as they are, apart from their indentation: one
+/// that follows code on a line stays there, and the rest go on lines of
+/// their own. Where a command follows a comment on a line, the command moves
+/// to the next line and the spaces before it are dropped. Blank lines between
+/// lines are kept.
+pub fn format(src: &str, width: usize) -> Result<String, CompileError> {
+    bfir::compile(src)?;
+
+    let mut w = Writer {
+        width,
+        ..Writer::default()
+    };
+    let tokens = bfir::tokenize(src);
+    for (k, &token) in tokens.iter().enumerate() {
+        match token {
+            Token::Command('[', _) => {
+                w.flush();
+                w.push("[");
+                w.depth += 1;
+            }
+            Token::Command(']', _) => {
+                w.flush();
+                w.depth -= 1;
+                w.push("]");
+            }
+            Token::Command(ch, _) => {
+                let full = w.depth * INDENT + w.line.len() >= w.width;
+                if !w.open || full {
+                    w.flush();
+                }
+                w.push(ch.encode_utf8(&mut [0; 4]));
+                w.open = true;
+            }
+            Token::Comment(text) => {
+                let lines: Vec<_> = text.split('\n').collect();
+                let last = lines.len() - 1;
+                for (i, line) in lines.iter().enumerate() {
+                    let mut line = line.trim_start();
+                    if i == last && k + 1 < tokens.len() {
+                        line = line.trim_end();
+                    }
+                    if line.is_empty() {
+                        // a line with nothing on it
+                        if i > 0 && i < last {
+                            w.flush();
+                            w.blanks += 1;
+                        }
+                        continue;
+                    }
+                    if i == 0 && !w.line.is_empty() && !w.commented {
+                        w.line += " ";
+                        w.line += line;
+                    } else {
+                        w.flush();
+                        w.push(line);
+                    }
+                    (w.open, w.commented) = (false, true);
+                }
+            }
+        }
+    }
+    w.flush();
+    Ok(w.out)
+}
+
+/// Returns the commands in `src` and nothing else.
+pub fn minify(src: &str) -> String {
+    bfir::tokenize(src)
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Command(ch, _) => Some(ch),
+            Token::Comment(_) => None,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Writer {
+    out: String,
+    width: usize,
+    depth: usize,
+    /// The line being written, without its indentation, and its depth.
+    line: String,
+    line_depth: usize,
+    /// Whether more commands may go on the line.
+    open: bool,
+    /// Whether the line ends in a comment.
+    commented: bool,
+    /// How many blank lines go before the next one.
+    blanks: usize,
+}
+
+impl Writer {
+    fn push(&mut self, text: &str) {
+        if self.line.is_empty() {
+            self.line_depth = self.depth;
+        }
+        self.line += text;
+    }
+
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        let blanks = std::mem::take(&mut self.blanks);
+        if !self.out.is_empty() {
+            self.out += &"\n".repeat(blanks);
+        }
+        self.out += &" ".repeat(self.line_depth * INDENT);
+        self.out += &std::mem::take(&mut self.line);
+        self.out.push('\n');
+        (self.open, self.commented) = (false, false);
+    }
+}
+
+#[test]
+fn test_format() {
+    let src = "init +++++ +++++  [ loop\n\t>++++ +++>++\n\n<<-]>++.   print\n>[-]]";
+    assert!(format(src, DEFAULT_WIDTH).is_err());
+
+    let src = &src[..src.len() - 1];
+    let text = format(src, 12).unwrap();
+    assert_eq!(
+        text.lines().collect::<Vec<_>>(),
+        [
+            "init",
+            "++++++++++",
+            "[ loop",
+            "    >+++++++",
+            "    >++",
+            "",
+            "    <<-",
+            "]",
+            ">++. print",
+            ">",
+            "[",
+            "    -",
+            "]",
+        ]
+    );
+    assert_eq!(minify(&text), "++++++++++[>+++++++>++<<-]>++.>[-]");
+
+    // comment lines keep their text and spacing, and blank lines stay
+    let src = "+[\n  two  spaces \n\n\n\t x\r\n-]";
+    assert_eq!(
+        format(src, DEFAULT_WIDTH).unwrap(),
+        "+\n[\n    two  spaces \n\n\n    x\r\n    -\n]\n"
+    );
+
+    // formatting what is already formatted changes nothing
+    for src in [
+        src,
+        "+[-[,.]>[<+>-]]",
+        "a\n\n\nb [c\n+\n\n]\n\n",
+        "[ ü [ 漢字 ] ]   ",
+        "+[\n  two  spaces \n\n\n\t x\r\n-] end \n",
+    ] {
+        for width in [1, 8, DEFAULT_WIDTH] {
+            let once = format(src, width).unwrap();
+            assert_eq!(format(&once, width).unwrap(), once);
+            assert_eq!(minify(&once), minify(src));
+        }
+    }
+}
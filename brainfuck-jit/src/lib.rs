@@ -0,0 +1,23 @@
+mod analysis;
+pub mod bfaot;
+pub mod bfdebug;
+mod bfinterp;
+pub mod bfio;
+pub mod bfir;
+pub mod bfjit;
+mod codegen;
+pub mod config;
+pub mod error;
+pub mod format;
+pub mod lint;
+pub mod nonblock;
+mod peval;
+pub mod snapshot;
+mod tape;
+pub mod transpile;
+
+pub use crate::bfio::run_to_string;
+pub use crate::bfir::{compile, optimize, BfIR};
+pub use crate::bfjit::{Backend, BfVM};
+pub use crate::config::{CellWidth, EofPolicy, VmConfig};
+pub use crate::error::VMError;
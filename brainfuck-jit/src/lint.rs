@@ -0,0 +1,192 @@
+use crate::bfir::{self, BfIR, CompileError, KnownCell, Position};
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// Something in a program that is legal but likely a mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+    /// Two adjacent ops that undo each other, such as `+-` or `<>`.
+    Cancelling(char, char),
+    /// A loop starting on a cell that is always zero there.
+    DeadLoop,
+    /// A loop that never changes the cell it tests.
+    EndlessLoop,
+    /// A loop that moves by this many cells and changes the cell it moves
+    /// onto, so it keeps going on blank cells.
+    RunawayLoop(i64),
+    /// A `,` that can never run.
+    DeadInput,
+    /// Code after a loop that is entered and never ends.
+    Unreachable,
+}
+
+impl Display for LintKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintKind::Cancelling(a, b) => write!(f, "`{}{}` cancels out", a, b),
+            LintKind::DeadLoop => write!(f, "this loop never runs, as the cell is always zero here"),
+            LintKind::EndlessLoop => write!(
+                f,
+                "this loop never changes the cell it tests, so it never ends once entered"
+            ),
+            LintKind::RunawayLoop(by) => write!(
+                f,
+                "this loop moves by {} and changes the cell it moves onto, so on blank cells it runs off the tape",
+                by
+            ),
+            LintKind::DeadInput => write!(f, "this `,` never runs"),
+            LintKind::Unreachable => write!(f, "this code never runs, as the loop before it never ends"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lint {
+    pub pos: Position,
+    pub kind: LintKind,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: warning: {}", self.pos, self.kind)
+    }
+}
+
+/// Compiles `src` with [`bfir::compile`] and returns what looks suspicious
+/// in it, in source order.
+pub fn lint(src: &str) -> Result<Vec<Lint>, CompileError> {
+    let (code, positions) = bfir::compile_with_positions(src)?;
+    let mut lints = vec![];
+    let mut warn = |pc: usize, kind| {
+        lints.push(Lint {
+            pos: positions[pc],
+            kind,
+        })
+    };
+
+    use BfIR::*;
+    let mut pc = 0;
+    while pc + 1 < code.len() {
+        let pair = match (code[pc], code[pc + 1]) {
+            (AddVal { .. }, SubVal { .. }) => Some(('+', '-')),
+            (SubVal { .. }, AddVal { .. }) => Some(('-', '+')),
+            (AddPtr(_), SubPtr(_)) => Some(('>', '<')),
+            (SubPtr(_), AddPtr(_)) => Some(('<', '>')),
+            _ => None,
+        };
+        match pair {
+            Some((a, b)) => {
+                warn(pc, LintKind::Cancelling(a, b));
+                pc += 2;
+            }
+            None => pc += 1,
+        }
+    }
+
+    let jumps = bfir::jump_table(&code);
+    let is_input = |pc: &usize| matches!(code[*pc], GetByte { .. });
+
+    let mut cell = KnownCell::new();
+    let mut depth = 0;
+    let mut pc = 0;
+    while pc < code.len() {
+        match code[pc] {
+            Jz if cell.value() == Some(0) => {
+                warn(pc, LintKind::DeadLoop);
+                let right = jumps[pc];
+                for pc in (pc..right).filter(is_input) {
+                    warn(pc, LintKind::DeadInput);
+                }
+                pc = right + 1;
+                continue;
+            }
+            Jz => {
+                let right = jumps[pc];
+                match simple_loop(&code[pc + 1..right]) {
+                    Some((0, 0)) => {
+                        warn(pc, LintKind::EndlessLoop);
+                        // entered for sure, so it is the last thing to run
+                        if depth == 0 && cell.value().is_some() && right + 1 < code.len() {
+                            warn(right + 1, LintKind::Unreachable);
+                            for pc in (right + 1..code.len()).filter(is_input) {
+                                warn(pc, LintKind::DeadInput);
+                            }
+                            break;
+                        }
+                    }
+                    Some((by, change)) if by != 0 && change != 0 => {
+                        warn(pc, LintKind::RunawayLoop(by))
+                    }
+                    _ => {}
+                }
+                depth += 1;
+            }
+            Jnz => depth -= 1,
+            _ => {}
+        }
+        cell.step(code[pc]);
+        pc += 1;
+    }
+
+    lints.sort_by_key(|l| l.pos);
+    Ok(lints)
+}
+
+/// For a loop body of only `+`, `-`, `<`, `>` and `.`, returns how far it
+/// moves the pointer and how much it changes the cell it ends up on.
+fn simple_loop(body: &[BfIR]) -> Option<(i64, i64)> {
+    let mut ptr = 0;
+    let mut changes = BTreeMap::new();
+    for &ir in body {
+        match ir {
+            BfIR::AddVal { .. } => *changes.entry(ptr).or_insert(0) += 1,
+            BfIR::SubVal { .. } => *changes.entry(ptr).or_insert(0) -= 1,
+            BfIR::AddPtr(_) => ptr += 1,
+            BfIR::SubPtr(_) => ptr -= 1,
+            BfIR::PutByte { .. } => {}
+            _ => return None,
+        }
+    }
+    Some((ptr, changes.get(&ptr).copied().unwrap_or(0)))
+}
+
+#[test]
+fn test_lint() {
+    use LintKind::*;
+
+    let kinds = |src: &str| {
+        let lints = lint(src).unwrap();
+        lints.iter().map(|l| l.kind).collect::<Vec<_>>()
+    };
+
+    assert_eq!(kinds(",[.,]"), []);
+    assert_eq!(
+        kinds("+-><,-+"),
+        [
+            Cancelling('+', '-'),
+            Cancelling('>', '<'),
+            Cancelling('-', '+')
+        ]
+    );
+    // a comment loop at the start, and a loop right after another
+    assert_eq!(kinds("[ a, b ],[-][.]"), [DeadLoop, DeadInput, DeadLoop]);
+    assert_eq!(kinds(",[>+<.],[>+]"), [EndlessLoop, RunawayLoop(1)]);
+    // a loop that is sure to be entered ends the program
+    assert_eq!(kinds("+[]>,"), [EndlessLoop, Unreachable, DeadInput]);
+    assert_eq!(kinds(",[[-]+[]]."), [EndlessLoop]);
+    // a cell that may have wrapped around to zero
+    assert_eq!(kinds(&("+".repeat(256) + "[.],")), [EndlessLoop]);
+    assert_eq!(
+        kinds(&("+".repeat(255) + "[.],")),
+        [EndlessLoop, Unreachable, DeadInput]
+    );
+    assert_eq!(kinds("+-[.]"), [Cancelling('+', '-'), DeadLoop]);
+
+    let lints = lint("+\n [].").unwrap();
+    assert_eq!(lints[0].pos, Position { line: 2, col: 2 });
+    assert_eq!(
+        lints[1].to_string(),
+        "2:4: warning: this code never runs, as the loop before it never ends"
+    );
+}
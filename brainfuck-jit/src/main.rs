@@ -1,8 +1,5 @@
-mod bfir;
-mod bfjit;
-mod error;
-
-use crate::bfjit::BfVM;
+use bfrs::bfdebug::Debugger;
+use bfrs::{Backend, BfVM, CellWidth, EofPolicy, VmConfig};
 
 use std::io::{stdin, stdout};
 use std::path::PathBuf;
@@ -10,31 +7,366 @@ use std::path::PathBuf;
 use clap::Parser;
 
 #[derive(Debug, clap::Parser)]
-#[clap(version)]
+#[clap(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Opt {
-    #[clap(name = "FILE")]
-    file_path: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(name = "FILE", required = true)]
+    file_path: Option<PathBuf>,
+
+    #[clap(flatten)]
+    run: RunOpt,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Compile to a standalone x86-64 Linux executable
+    Build {
+        #[clap(name = "FILE")]
+        file_path: PathBuf,
+
+        #[clap(
+            long = "out",
+            value_name = "PATH",
+            help = "Executable to write [default: FILE without its extension, or FILE.out]"
+        )]
+        out: Option<PathBuf>,
+
+        #[clap(flatten)]
+        compile: CompileOpt,
+    },
+    /// Step through a program and inspect its tape; every `#` is a breakpoint
+    Debug {
+        #[clap(name = "FILE")]
+        file_path: PathBuf,
+
+        #[clap(
+            long = "input",
+            value_name = "PATH",
+            help = "Read the program's input from a file instead of stdin"
+        )]
+        input: Option<PathBuf>,
+
+        #[clap(flatten)]
+        tape: TapeOpt,
+    },
+    /// Warn about constructs that are likely mistakes; fails if there are any
+    Lint {
+        #[clap(name = "FILE", required = true)]
+        file_paths: Vec<PathBuf>,
+    },
+    /// Re-indent programs by loop depth, rewriting them in place
+    Fmt {
+        #[clap(name = "FILE", required = true)]
+        file_paths: Vec<PathBuf>,
+
+        #[clap(
+            long = "width",
+            default_value_t = bfrs::format::DEFAULT_WIDTH,
+            help = "Columns to wrap runs of commands at"
+        )]
+        width: usize,
+
+        #[clap(
+            long = "check",
+            help = "Fail if a file is not formatted, instead of rewriting it"
+        )]
+        check: bool,
+    },
+    /// Print a program with everything but its commands stripped
+    Minify {
+        #[clap(name = "FILE")]
+        file_path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Emit {
+    /// C source
+    C,
+    /// Rust source
+    Rust,
+    /// The IR, with the source position of each op
+    Ir,
+    /// The JIT's machine code, headed by the op each part comes from
+    Asm,
+}
+
+#[derive(Debug, clap::Args)]
+struct RunOpt {
+    #[clap(
+        long = "backend",
+        value_enum,
+        default_value_t,
+        help = "Execution backend"
+    )]
+    backend: Backend,
+
+    #[clap(
+        long = "emit",
+        value_enum,
+        help = "Print the program in another form instead of running it"
+    )]
+    emit: Option<Emit>,
 
+    #[clap(
+        long = "budget",
+        value_name = "JUMPS",
+        help = "Fail after this many `]`"
+    )]
+    budget: Option<u64>,
+
+    #[clap(
+        long = "guard-pages",
+        help = "Catch pointer overflows with guard pages around the tape instead of bounds checks"
+    )]
+    guard_pages: bool,
+
+    #[clap(flatten)]
+    compile: CompileOpt,
+}
+
+#[derive(Debug, clap::Args)]
+struct CompileOpt {
     #[clap(short = 'o', long = "optimize", help = "Optimize code")]
     optimize: bool,
+
+    #[clap(flatten)]
+    tape: TapeOpt,
+
+    #[clap(
+        long = "register-cell",
+        help = "Keep the current cell in a register in compiled code"
+    )]
+    register_cell: bool,
 }
 
-fn main() {
-    let opt = Opt::parse();
+#[derive(Debug, clap::Args)]
+struct TapeOpt {
+    #[clap(
+        long = "tape-size",
+        default_value_t = bfrs::config::DEFAULT_TAPE_SIZE,
+        help = "Number of cells on the tape"
+    )]
+    tape_size: usize,
+
+    #[clap(
+        long = "cell-width",
+        value_enum,
+        default_value_t,
+        help = "Bits per cell"
+    )]
+    cell_width: CellWidth,
+
+    #[clap(
+        long = "tape-origin",
+        default_value_t = 0,
+        help = "Cell the pointer starts at"
+    )]
+    tape_origin: usize,
+
+    #[clap(
+        long = "eof",
+        value_enum,
+        default_value_t,
+        help = "What `,` stores at end of input"
+    )]
+    eof: EofPolicy,
+}
+
+impl CompileOpt {
+    fn config(&self) -> VmConfig {
+        self.tape.config().register_cell(self.register_cell)
+    }
+}
+
+impl TapeOpt {
+    fn config(&self) -> VmConfig {
+        VmConfig::new()
+            .tape_size(self.tape_size)
+            .cell_width(self.cell_width)
+            .tape_origin(self.tape_origin)
+            .eof(self.eof)
+    }
+}
+
+fn run(file_path: PathBuf, opt: RunOpt) -> bfrs::error::Result<()> {
+    let mut config = opt
+        .compile
+        .config()
+        .backend(opt.backend)
+        .guard_pages(opt.guard_pages);
+    if let Some(budget) = opt.budget {
+        config = config.budget(budget);
+    }
+
+    if let Some(emit) = opt.emit {
+        let src = std::fs::read_to_string(&file_path)?;
+        let (mut ir, mut positions) =
+            bfrs::bfir::compile_with_positions(&src).map_err(|e| e.with_path(&file_path))?;
+        if opt.compile.optimize {
+            bfrs::bfir::optimize_with_positions(&mut ir, &mut positions);
+        }
+        let text = match emit {
+            Emit::C => bfrs::transpile::to_c(&ir, &config)?,
+            Emit::Rust => bfrs::transpile::to_rust(&ir, &config)?,
+            Emit::Ir => bfrs::bfir::pretty(&ir, &positions),
+            Emit::Asm => {
+                let vm = BfVM::from_ir_with_positions(
+                    ir,
+                    positions,
+                    Box::new(std::io::empty()),
+                    Box::new(std::io::sink()),
+                    config.backend(Backend::Jit),
+                )?;
+                vm.disassemble().unwrap()
+            }
+        };
+        print!("{}", text);
+        return Ok(());
+    }
 
     let stdin = stdin();
     let stdout = stdout();
 
-    let ret = BfVM::new(
-        &opt.file_path,
+    BfVM::new(
+        &file_path,
         Box::new(stdin.lock()),
         Box::new(stdout.lock()),
-        opt.optimize,
+        opt.compile.optimize,
+        config,
     )
-    .and_then(|mut vm| vm.run());
+    .and_then(|mut vm| vm.run())
+}
+
+fn build(file_path: PathBuf, out: Option<PathBuf>, opt: CompileOpt) -> bfrs::error::Result<()> {
+    let src = std::fs::read_to_string(&file_path)?;
+    let mut ir = bfrs::compile(&src).map_err(|e| e.with_path(&file_path))?;
+    if opt.optimize {
+        bfrs::optimize(&mut ir);
+    }
+    let elf = bfrs::bfaot::build(&ir, &opt.config())?;
+
+    let out = out.unwrap_or_else(|| match file_path.extension() {
+        Some(_) => file_path.with_extension(""),
+        None => file_path.with_extension("out"),
+    });
+    std::fs::write(&out, elf)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&out, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+fn debug(file_path: PathBuf, input: Option<PathBuf>, opt: TapeOpt) -> bfrs::error::Result<()> {
+    let src = std::fs::read_to_string(&file_path)?;
+    let input: Box<dyn std::io::Read> = match input {
+        Some(path) => Box::new(std::fs::File::open(path)?),
+        None => Box::new(stdin()),
+    };
+
+    let mut dbg =
+        Debugger::new(&src, input, Box::new(stdout()), opt.config()).map_err(|e| match e {
+            bfrs::VMError::Compile(e) => bfrs::VMError::Compile(e.with_path(&file_path)),
+            e => e,
+        })?;
+    bfrs::bfdebug::repl(&mut dbg, |line| stdin().read_line(line), stdout())
+}
+
+fn lint(file_paths: Vec<PathBuf>) -> bfrs::error::Result<()> {
+    let mut clean = true;
+    for file_path in file_paths {
+        let src = std::fs::read_to_string(&file_path)?;
+        match bfrs::lint::lint(&src) {
+            Ok(lints) => {
+                for lint in &lints {
+                    println!("{}:{}", file_path.display(), lint);
+                }
+                clean &= lints.is_empty();
+            }
+            Err(e) => {
+                eprintln!("{}", e.with_path(&file_path));
+                clean = false;
+            }
+        }
+    }
+    if !clean {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn fmt(file_paths: Vec<PathBuf>, width: usize, check: bool) -> bfrs::error::Result<()> {
+    let mut clean = true;
+    for file_path in file_paths {
+        let src = std::fs::read_to_string(&file_path)?;
+        let text = match bfrs::format::format(&src, width) {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("{}", e.with_path(&file_path));
+                clean = false;
+                continue;
+            }
+        };
+        if text == src {
+            continue;
+        }
+        if check {
+            println!("{}: not formatted", file_path.display());
+            clean = false;
+        } else {
+            std::fs::write(&file_path, text)?;
+        }
+    }
+    if !clean {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn minify(file_path: PathBuf) -> bfrs::error::Result<()> {
+    let src = std::fs::read_to_string(file_path)?;
+    println!("{}", bfrs::format::minify(&src));
+    Ok(())
+}
+
+fn main() {
+    let opt = Opt::parse();
+
+    let ret = match opt.command {
+        Some(Command::Build {
+            file_path,
+            out,
+            compile,
+        }) => build(file_path, out, compile),
+        Some(Command::Debug {
+            file_path,
+            input,
+            tape,
+        }) => debug(file_path, input, tape),
+        Some(Command::Lint { file_paths }) => lint(file_paths),
+        Some(Command::Fmt {
+            file_paths,
+            width,
+            check,
+        }) => fmt(file_paths, width, check),
+        Some(Command::Minify { file_path }) => minify(file_path),
+        None => run(opt.file_path.unwrap(), opt.run),
+    };
 
-    if let Err(e) = &ret {
-        eprintln!("bfjit: {}", e);
+    match &ret {
+        Err(bfrs::VMError::Compile(e)) => eprintln!("{}", e),
+        Err(e) => eprintln!("bfjit: {}", e),
+        Ok(()) => {}
     }
 
     std::process::exit(ret.is_err() as i32)
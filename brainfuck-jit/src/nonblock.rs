@@ -0,0 +1,189 @@
+use crate::bfjit::BfVM;
+use crate::config::VmConfig;
+use crate::error::{Result, RuntimeError, VMError};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+
+/// Why [`NonBlockingVM::resume`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The program ran to its end.
+    Finished,
+    /// The program is waiting at a `,` for bytes that have not been fed yet.
+    NeedInput,
+}
+
+#[derive(Default)]
+struct Input {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Reads what the host fed, and would block once that runs out until the
+/// input is closed.
+#[derive(Clone, Default)]
+struct InputQueue(Rc<RefCell<Input>>);
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut input = self.0.borrow_mut();
+        if input.bytes.is_empty() && !input.closed {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        input.bytes.read(buf)
+    }
+}
+
+#[derive(Clone, Default)]
+struct OutputQueue(Rc<RefCell<Vec<u8>>>);
+
+impl Write for OutputQueue {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A [`BfVM`] that never blocks on I/O, for event loops. The host feeds
+/// input and drains output between calls to [`resume`](Self::resume).
+pub struct NonBlockingVM {
+    vm: BfVM<'static>,
+    input: InputQueue,
+    output: OutputQueue,
+}
+
+impl NonBlockingVM {
+    pub fn from_source(src: &str, optimize: bool, config: VmConfig) -> Result<Self> {
+        let input = InputQueue::default();
+        let output = OutputQueue::default();
+        let vm = BfVM::from_source(
+            src,
+            Box::new(input.clone()),
+            Box::new(output.clone()),
+            optimize,
+            config,
+        )?;
+        Ok(Self { vm, input, output })
+    }
+
+    /// Makes `bytes` available to the program.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.0.borrow_mut().bytes.extend(bytes);
+    }
+
+    /// Ends the input once the program has read what was fed, so `,` sees
+    /// end of input instead of waiting.
+    pub fn close_input(&mut self) {
+        self.input.0.borrow_mut().closed = true;
+    }
+
+    /// Runs until the program ends or needs input that has not been fed.
+    /// Other errors, like a [`Timeout`](RuntimeError::Timeout), leave the
+    /// program where it stopped as [`BfVM::run`] does.
+    pub fn resume(&mut self) -> Result<Status> {
+        match self.vm.run() {
+            Ok(()) => Ok(Status::Finished),
+            Err(VMError::Runtime(RuntimeError::NeedInput)) => Ok(Status::NeedInput),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes what the program has printed so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut *self.output.0.borrow_mut())
+    }
+
+    pub fn vm(&mut self) -> &mut BfVM<'static> {
+        &mut self.vm
+    }
+
+    /// Runs the program to its end, awaiting `input` whenever it runs out of
+    /// bytes and handing everything it prints to `output`. `input` returns
+    /// `None` to end the input.
+    pub async fn run_async<I, F>(
+        &mut self,
+        mut input: I,
+        mut output: impl FnMut(Vec<u8>),
+    ) -> Result<()>
+    where
+        I: FnMut() -> F,
+        F: Future<Output = Option<Vec<u8>>>,
+    {
+        loop {
+            let status = self.resume();
+            let printed = self.take_output();
+            if !printed.is_empty() {
+                output(printed);
+            }
+            match status? {
+                Status::Finished => return Ok(()),
+                Status::NeedInput => match input().await {
+                    Some(bytes) => self.feed(&bytes),
+                    None => self.close_input(),
+                },
+            }
+        }
+    }
+}
+
+#[test]
+fn test_nonblocking() {
+    use crate::bfjit::Backend;
+    use crate::config::EofPolicy;
+
+    // echo the input, upper-cased
+    let src = ",[>++++[<-------->-]<.,]";
+    for backend in [Backend::Interp, Backend::Jit] {
+        for optimize in [false, true] {
+            let config = VmConfig::new().backend(backend).eof(EofPolicy::Zero);
+            let mut vm = NonBlockingVM::from_source(src, optimize, config.clone()).unwrap();
+            assert_eq!(vm.resume().unwrap(), Status::NeedInput);
+            vm.feed(b"ab");
+            assert_eq!(vm.resume().unwrap(), Status::NeedInput);
+            assert_eq!(vm.take_output(), b"AB");
+            vm.feed(b"c");
+            assert_eq!(vm.resume().unwrap(), Status::NeedInput);
+            assert_eq!(vm.take_output(), b"C");
+            vm.close_input();
+            assert_eq!(vm.resume().unwrap(), Status::Finished);
+            assert!(vm.take_output().is_empty());
+
+            // a snapshot taken while waiting resumes at the `,`
+            let mut vm = NonBlockingVM::from_source(src, optimize, config.clone()).unwrap();
+            vm.feed(b"x");
+            assert_eq!(vm.resume().unwrap(), Status::NeedInput);
+            let snapshot = vm.vm().snapshot();
+            let mut vm = NonBlockingVM::from_source(src, optimize, config.clone()).unwrap();
+            vm.vm().restore(&snapshot).unwrap();
+            vm.feed(b"yz");
+            vm.close_input();
+            assert_eq!(vm.resume().unwrap(), Status::Finished);
+            assert_eq!(vm.take_output(), b"YZ");
+
+            let mut vm = NonBlockingVM::from_source(src, optimize, config).unwrap();
+            let mut chunks = vec![b"hi".to_vec(), b"there".to_vec()].into_iter();
+            let mut output = vec![];
+            {
+                let fut = vm.run_async(
+                    || std::future::ready(chunks.next()),
+                    |bytes| output.extend(bytes),
+                );
+                let mut fut = std::pin::pin!(fut);
+                let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+                assert!(matches!(
+                    fut.as_mut().poll(&mut cx),
+                    std::task::Poll::Ready(Ok(()))
+                ));
+            }
+            assert_eq!(output, b"HITHERE");
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::bfir::{self, BfIR};
+use crate::config::VmConfig;
+
+use std::collections::BTreeMap;
+
+/// Most ops the evaluation runs before it gives up on reaching input.
+const STEP_LIMIT: u64 = 1 << 20;
+
+/// What running the opening stretch of a program on a blank tape leaves
+/// behind. It depends on nothing the program reads, so it can be computed
+/// once, ahead of the run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Prefix {
+    /// Index of the op the rest of the run starts at.
+    pub(crate) pc: usize,
+    pub(crate) ptr: usize,
+    /// Cells that are no longer zero, in order.
+    pub(crate) cells: Vec<(usize, u32)>,
+    /// What the prefix prints.
+    pub(crate) output: Vec<u8>,
+}
+
+/// Runs `code` from the start until its first `,`, the end, an error or
+/// [`STEP_LIMIT`], whichever comes first, and returns the state at the last
+/// op it got to that a run can start at: outside of any loop and at the head
+/// of a straight-line block. Returns `None` when that is the first op, or
+/// when the config has a budget, which counts every `]` of the prefix.
+pub(crate) fn eval_prefix(code: &[BfIR], config: &VmConfig) -> Option<Prefix> {
+    if config.budget.is_some() {
+        return None;
+    }
+    let mut eval = Eval::new(code, config);
+    eval.run(None);
+    let pc = eval.cut;
+    if pc == 0 {
+        return None;
+    }
+
+    // top-level ops run at most once, so the run stops there the same way
+    let mut eval = Eval::new(code, config);
+    eval.run(Some(pc));
+    Some(Prefix {
+        pc,
+        ptr: eval.ptr,
+        cells: eval.tape.into_iter().filter(|&(_, v)| v != 0).collect(),
+        output: eval.output,
+    })
+}
+
+struct Eval<'a> {
+    code: &'a [BfIR],
+    jumps: Vec<usize>,
+    len: usize,
+    mask: u32,
+    ptr: usize,
+    tape: BTreeMap<usize, u32>,
+    output: Vec<u8>,
+    /// The last op seen that a run can start at.
+    cut: usize,
+}
+
+impl<'a> Eval<'a> {
+    fn new(code: &'a [BfIR], config: &VmConfig) -> Self {
+        let jumps = bfir::jump_table(code);
+
+        Self {
+            code,
+            jumps,
+            len: config.tape_size,
+            mask: (u64::MAX >> (64 - 8 * config.cell_width.bytes())) as u32,
+            ptr: config.tape_origin,
+            tape: BTreeMap::new(),
+            output: vec![],
+            cut: 0,
+        }
+    }
+
+    fn load(&self, p: usize) -> u32 {
+        self.tape.get(&p).copied().unwrap_or(0)
+    }
+
+    fn store(&mut self, p: usize, val: u32) {
+        self.tape.insert(p, val & self.mask);
+    }
+
+    fn at(&self, offset: i64) -> Option<usize> {
+        self.ptr
+            .checked_add_signed(offset as isize)
+            .filter(|&p| p < self.len)
+    }
+
+    /// Runs until `stop`, if given, or as far as the evaluation can go.
+    fn run(&mut self, stop: Option<usize>) {
+        let mut pc = 0;
+        let mut depth = 0;
+        for _ in 0..STEP_LIMIT {
+            if depth == 0 && is_entry(self.code, pc) {
+                self.cut = pc;
+            }
+            if Some(pc) == stop || pc == self.code.len() || self.step(&mut pc, &mut depth).is_none()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Runs the op at `pc`, or returns `None` at a `,` or an error.
+    fn step(&mut self, pc: &mut usize, depth: &mut usize) -> Option<()> {
+        use BfIR::*;
+        match self.code[*pc] {
+            AddPtr(x) => self.ptr = self.at(x as i64)?,
+            SubPtr(x) => self.ptr = self.at(-(x as i64))?,
+            AddVal { offset, val } => {
+                let p = self.at(offset as i64)?;
+                self.store(p, self.load(p).wrapping_add(val));
+            }
+            SubVal { offset, val } => {
+                let p = self.at(offset as i64)?;
+                self.store(p, self.load(p).wrapping_sub(val));
+            }
+            GetByte { .. } => return None,
+            PutByte { offset } => {
+                let p = self.at(offset as i64)?;
+                self.output.push(self.load(p) as u8);
+            }
+            SetZero => self.store(self.ptr, 0),
+            SetValue(val) => self.store(self.ptr, val),
+            MulAdd { offset, factor } => {
+                let val = self.load(self.ptr);
+                if val != 0 {
+                    let p = self.at(offset as i64)?;
+                    self.store(p, self.load(p).wrapping_add(val.wrapping_mul(factor)));
+                }
+            }
+            ScanRight(x) | ScanLeft(x) => {
+                if self.load(self.ptr) != 0 {
+                    let stride = match self.code[*pc] {
+                        ScanRight(_) => x as i64,
+                        _ => -(x as i64),
+                    };
+                    // one move per step, so long scans count against the limit
+                    self.ptr = self.at(stride)?;
+                    return Some(());
+                }
+            }
+            Jz => {
+                if self.load(self.ptr) == 0 {
+                    *pc = self.jumps[*pc];
+                } else {
+                    *depth += 1;
+                }
+            }
+            Jnz => {
+                if self.load(self.ptr) != 0 {
+                    *pc = self.jumps[*pc];
+                } else {
+                    *depth -= 1;
+                }
+            }
+        }
+        *pc += 1;
+        Some(())
+    }
+}
+
+/// Whether compiled code can start at `code[pc]`: it must not be in the
+/// middle of a straight-line block, whose check ran at the block's head.
+pub(crate) fn is_entry(code: &[BfIR], pc: usize) -> bool {
+    use BfIR::*;
+    let block_op = |ir| matches!(ir, AddPtr(_) | SubPtr(_) | AddVal { .. } | SubVal { .. });
+    match (pc.checked_sub(1).map(|i| code[i]), code.get(pc)) {
+        (Some(prev), Some(&ir)) => {
+            !(block_op(prev) && (block_op(ir) || matches!(ir, GetByte { .. } | PutByte { .. })))
+        }
+        _ => true,
+    }
+}
+
+#[test]
+fn test_eval_prefix() {
+    let config = VmConfig::new();
+    let prefix = |src| eval_prefix(&crate::compile(src).unwrap(), &config);
+
+    // stops at the `,`
+    let p = prefix("++++++++[>++++++++<-]>+.+.,.").unwrap();
+    assert_eq!((p.pc, p.ptr), (26, 1));
+    assert_eq!(p.cells, [(1, 66)]);
+    assert_eq!(p.output, b"AB");
+
+    // and before the loop around it, or the block it is in
+    assert_eq!(prefix("+.[,]").unwrap().pc, 2);
+    assert_eq!(prefix("+.>+<,").unwrap().pc, 2);
+    assert_eq!(prefix(",+."), None);
+
+    // or at the head of a block that fails, or after the last op
+    assert_eq!(prefix(".+<").unwrap().pc, 1);
+    assert_eq!(prefix("[-]-.").unwrap().pc, 5);
+
+    // a budget counts the prefix's `]` too
+    let config = config.budget(100);
+    assert_eq!(eval_prefix(&crate::compile("+.").unwrap(), &config), None);
+}
@@ -0,0 +1,244 @@
+use crate::bfir::BfIR;
+use crate::config::CellWidth;
+use crate::error::{Result, VMError};
+
+const MAGIC: &[u8; 4] = b"BFS1";
+
+/// The largest tape [`Snapshot::from_bytes`] decodes, in bytes, so a bad
+/// header cannot make it allocate without bound.
+const MAX_TAPE_BYTES: u64 = 1 << 30;
+
+/// The state of a [`BfVM`](crate::BfVM) between two runs: the tape, the
+/// pointer, and the op the next run starts at.
+///
+/// [`BfVM::restore`](crate::BfVM::restore) only accepts it for the same
+/// program, cell width and tape size, on either backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub(crate) cell_width: CellWidth,
+    /// Hash of the IR the snapshot was taken from.
+    pub(crate) fingerprint: u64,
+    pub(crate) pc: usize,
+    pub(crate) ptr: usize,
+    /// The tape as little-endian cells.
+    pub(crate) tape: Box<[u8]>,
+}
+
+impl Snapshot {
+    pub fn cell_width(&self) -> CellWidth {
+        self.cell_width
+    }
+
+    /// Index of the op the next run starts at.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The cell the pointer is on.
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// The tape as little-endian cells.
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    /// Encodes the snapshot. After a fixed header, the tape is stored as runs
+    /// of equal cells, so a mostly blank tape takes a few bytes.
+    ///
+    /// ```text
+    /// "BFS1" | cell bytes: u8 | fingerprint, pc, ptr, cells: u64 LE
+    ///        | (run length, cell value: LEB128)*
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let width = self.cell_width.bytes();
+        let mut out = Vec::with_capacity(40);
+        out.extend_from_slice(MAGIC);
+        out.push(width as u8);
+        out.extend_from_slice(&self.fingerprint.to_le_bytes());
+        out.extend_from_slice(&(self.pc as u64).to_le_bytes());
+        out.extend_from_slice(&(self.ptr as u64).to_le_bytes());
+        out.extend_from_slice(&((self.tape.len() / width) as u64).to_le_bytes());
+
+        let mut cells = self.tape.chunks_exact(width).map(cell_value).peekable();
+        while let Some(value) = cells.next() {
+            let mut run = 1;
+            while cells.next_if_eq(&value).is_some() {
+                run += 1;
+            }
+            write_leb128(&mut out, run);
+            write_leb128(&mut out, value as u64);
+        }
+        out
+    }
+
+    /// Decodes a snapshot written by [`to_bytes`](Self::to_bytes), of a tape
+    /// of at most 1 GiB.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let invalid = VMError::InvalidSnapshot;
+
+        if bytes.get(..4) != Some(MAGIC) {
+            return Err(invalid("not a snapshot"));
+        }
+        bytes = &bytes[4..];
+        let cell_width = match read_u8(&mut bytes)? {
+            1 => CellWidth::U8,
+            2 => CellWidth::U16,
+            4 => CellWidth::U32,
+            _ => return Err(invalid("bad cell width")),
+        };
+        let width = cell_width.bytes();
+        let fingerprint = read_u64(&mut bytes)?;
+        let pc = read_u64(&mut bytes)?
+            .try_into()
+            .map_err(|_| invalid("bad pc"))?;
+        let ptr = read_u64(&mut bytes)?
+            .try_into()
+            .map_err(|_| invalid("bad ptr"))?;
+        let cells = read_u64(&mut bytes)?;
+        if cells.saturating_mul(width as u64) > MAX_TAPE_BYTES {
+            return Err(invalid("tape too large"));
+        }
+
+        let mut tape = vec![];
+        let mut left = cells;
+        while left > 0 {
+            let run = read_leb128(&mut bytes)?;
+            let value = read_leb128(&mut bytes)?;
+            if run == 0 || run > left {
+                return Err(invalid("bad run length"));
+            }
+            if value >> (8 * width) != 0 {
+                return Err(invalid("cell value too wide"));
+            }
+            let cell = &(value as u32).to_le_bytes()[..width];
+            tape.extend(cell.iter().cycle().take(run as usize * width));
+            left -= run;
+        }
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        Ok(Self {
+            cell_width,
+            fingerprint,
+            pc,
+            ptr,
+            tape: tape.into_boxed_slice(),
+        })
+    }
+}
+
+/// FNV-1a hash of `ir`, to tell programs apart.
+pub(crate) fn fingerprint(ir: &[BfIR]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for &b in bytes {
+            hash = (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+
+    use BfIR::*;
+    for &op in ir {
+        let (tag, a, b): (u8, i64, u32) = match op {
+            AddPtr(x) => (0, x as i64, 0),
+            SubPtr(x) => (1, x as i64, 0),
+            AddVal { offset, val } => (2, offset as i64, val),
+            SubVal { offset, val } => (3, offset as i64, val),
+            GetByte { offset } => (4, offset as i64, 0),
+            PutByte { offset } => (5, offset as i64, 0),
+            Jz => (6, 0, 0),
+            Jnz => (7, 0, 0),
+            SetZero => (8, 0, 0),
+            ScanRight(x) => (9, x as i64, 0),
+            ScanLeft(x) => (10, x as i64, 0),
+            MulAdd { offset, factor } => (11, offset as i64, factor),
+            SetValue(val) => (12, 0, val),
+        };
+        feed(&[tag]);
+        feed(&a.to_le_bytes());
+        feed(&b.to_le_bytes());
+    }
+    hash
+}
+
+fn cell_value(cell: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf[..cell.len()].copy_from_slice(cell);
+    u32::from_le_bytes(buf)
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn read_leb128(bytes: &mut &[u8]) -> Result<u64> {
+    let mut x = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let b = read_u8(bytes)?;
+        x |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(VMError::InvalidSnapshot("bad varint"))
+}
+
+fn read_u8(bytes: &mut &[u8]) -> Result<u8> {
+    let (&b, rest) = bytes
+        .split_first()
+        .ok_or(VMError::InvalidSnapshot("truncated"))?;
+    *bytes = rest;
+    Ok(b)
+}
+
+fn read_u64(bytes: &mut &[u8]) -> Result<u64> {
+    let (head, rest) = bytes
+        .split_first_chunk::<8>()
+        .ok_or(VMError::InvalidSnapshot("truncated"))?;
+    *bytes = rest;
+    Ok(u64::from_le_bytes(*head))
+}
+
+#[test]
+fn test_snapshot_bytes() {
+    let mut tape = vec![0_u8; 2 * 1000];
+    tape[2..4].copy_from_slice(&300_u16.to_le_bytes());
+    tape[1998..].copy_from_slice(&u16::MAX.to_le_bytes());
+    let snapshot = Snapshot {
+        cell_width: CellWidth::U16,
+        fingerprint: fingerprint(&crate::compile("+[>+<-]").unwrap()),
+        pc: 6,
+        ptr: 999,
+        tape: tape.into_boxed_slice(),
+    };
+
+    let bytes = snapshot.to_bytes();
+    assert!(bytes.len() < 50, "{}", bytes.len());
+    assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+    // a header claiming more cells than there are, or far too many
+    let header = |cells: u64| [&bytes[..29], &cells.to_le_bytes()].concat();
+    let mut huge = header(u64::MAX / 2);
+    write_leb128(&mut huge, u64::MAX / 2);
+    write_leb128(&mut huge, 0);
+
+    for bad in [
+        &bytes[..bytes.len() - 1],
+        &bytes[1..],
+        &[bytes.as_slice(), &[0]].concat(),
+        &[&header(1001), &bytes[37..]].concat(),
+        &header(1000),
+        &huge,
+    ] {
+        assert!(matches!(
+            Snapshot::from_bytes(bad),
+            Err(VMError::InvalidSnapshot(_))
+        ));
+    }
+}
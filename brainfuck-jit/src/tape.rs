@@ -0,0 +1,172 @@
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr;
+use std::sync::Once;
+
+/// Largest guard the JIT relies on. Ops that reach further keep their
+/// bounds checks.
+pub(crate) const MAX_GUARD: usize = 1 << 30;
+
+pub(crate) fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// The memory behind the cells.
+pub(crate) enum Tape {
+    Heap(Box<[u8]>),
+    /// Between two runs of `guard` inaccessible bytes.
+    Guarded {
+        base: *mut u8,
+        len: usize,
+        guard: usize,
+    },
+}
+
+impl Tape {
+    /// Maps `len` zeroed bytes with `guard` bytes of `PROT_NONE` on each
+    /// side. `len` and `guard` are multiples of the page size.
+    pub(crate) fn guarded(len: usize, guard: usize) -> std::io::Result<Self> {
+        let map_len = len + 2 * guard;
+        unsafe {
+            let base = libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
+            let base = base as *mut u8;
+            let tape = Self::Guarded { base, len, guard };
+            if libc::mprotect(
+                base.add(guard) as *mut c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(tape)
+        }
+    }
+
+    /// The inaccessible ranges below and above the cells.
+    pub(crate) fn guards(&self) -> [Range<usize>; 2] {
+        match *self {
+            Self::Heap(_) => [0..0, 0..0],
+            Self::Guarded { base, len, guard } => {
+                let start = base as usize + guard;
+                [start - guard..start, start + len..start + len + guard]
+            }
+        }
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Self::Heap(ref cells) => cells,
+            Self::Guarded { base, len, guard } => unsafe {
+                std::slice::from_raw_parts(base.add(guard), len)
+            },
+        }
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match *self {
+            Self::Heap(ref mut cells) => cells,
+            Self::Guarded { base, len, guard } => unsafe {
+                std::slice::from_raw_parts_mut(base.add(guard), len)
+            },
+        }
+    }
+}
+
+impl Drop for Tape {
+    fn drop(&mut self) {
+        if let Self::Guarded { base, len, guard } = *self {
+            unsafe { libc::munmap(base as *mut c_void, len + 2 * guard) };
+        }
+    }
+}
+
+/// Compiled code running on this thread whose faults on a guard page are
+/// pointer overflows.
+pub(crate) struct Fence {
+    pub(crate) code: Range<usize>,
+    pub(crate) guards: [Range<usize>; 2],
+    /// Address of the code's `->overflow` handler.
+    pub(crate) overflow: usize,
+}
+
+thread_local! {
+    static FENCE: Cell<*const Fence> = const { Cell::new(ptr::null()) };
+}
+
+static INSTALL: Once = Once::new();
+static mut PREV_ACTION: Option<libc::sigaction> = None;
+
+/// Runs `f` with a `SIGSEGV` handler that sends faults on the guard pages
+/// in `fence` to its overflow handler.
+pub(crate) fn fenced<R>(fence: &Fence, f: impl FnOnce() -> R) -> R {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut prev: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, &action, &mut prev);
+        PREV_ACTION = Some(prev);
+    });
+
+    FENCE.set(fence);
+    let ret = f();
+    FENCE.set(ptr::null());
+    ret
+}
+
+unsafe extern "C" fn on_segv(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let fence = FENCE.get();
+    if !fence.is_null() {
+        let fence = &*fence;
+        let gregs = &mut (*(ctx as *mut libc::ucontext_t)).uc_mcontext.gregs;
+        let rip = gregs[libc::REG_RIP as usize] as usize;
+        let addr = (*info).si_addr() as usize;
+        if fence.code.contains(&rip) && fence.guards.iter().any(|g| g.contains(&addr)) {
+            // return into `->overflow` as if the faulting instruction had
+            // called it; one byte in, the address still falls inside that
+            // instruction's op
+            let rsp = gregs[libc::REG_RSP as usize] as usize - 8;
+            *(rsp as *mut usize) = rip + 1;
+            gregs[libc::REG_RSP as usize] = rsp as i64;
+            gregs[libc::REG_RIP as usize] = fence.overflow as i64;
+            return;
+        }
+    }
+
+    // not ours: pass it on to whoever had it before
+    let prev = (*ptr::addr_of!(PREV_ACTION)).unwrap();
+    match prev.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // the fault repeats and takes the default action
+            libc::signal(sig, libc::SIG_DFL);
+        }
+        handler if prev.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) =
+                std::mem::transmute(handler);
+            handler(sig, info, ctx)
+        }
+        handler => {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+            handler(sig)
+        }
+    }
+}
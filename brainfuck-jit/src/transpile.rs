@@ -0,0 +1,351 @@
+use crate::bfir::BfIR;
+use crate::bfjit::check_loops;
+use crate::config::{CellWidth, EofPolicy, VmConfig};
+use crate::error::{Result, VMError};
+
+use std::fmt::Write;
+
+const C_PRELUDE: &str = r#"static cell tape[TAPE_SIZE];
+static size_t p = TAPE_ORIGIN;
+
+static void fail(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "bfjit: %s\n", msg);
+    exit(1);
+}
+
+static inline size_t at(long long offset) {
+    long long i = (long long)p + offset;
+    if (i < 0 || i >= TAPE_SIZE) fail("Runtime: Pointer overflow");
+    return (size_t)i;
+}
+
+static inline void shift(long long offset) { p = at(offset); }
+static inline void add(long long offset, uint32_t val) { tape[at(offset)] += val; }
+static inline void sub(long long offset, uint32_t val) { tape[at(offset)] -= val; }
+static inline void set_zero(void) { tape[p] = 0; }
+static inline void set(uint32_t val) { tape[p] = val; }
+
+static inline void mul_add(long long offset, uint32_t factor) {
+    if (tape[p]) tape[at(offset)] += (uint32_t)tape[p] * factor;
+}
+
+static inline void scan(long long stride) {
+    while (tape[p]) shift(stride);
+}
+
+static inline void put(long long offset) { putchar((unsigned char)tape[at(offset)]); }
+"#;
+
+const RUST_PRELUDE: &str = r#"use std::io::{BufWriter, Read, Stdout, Write};
+
+struct Vm {
+    tape: Vec<Cell>,
+    p: usize,
+    output: BufWriter<Stdout>,
+}
+
+impl Vm {
+    fn fail(&mut self, msg: &str) -> ! {
+        let _ = self.output.flush();
+        eprintln!("bfjit: {}", msg);
+        std::process::exit(1)
+    }
+
+    fn at(&mut self, offset: isize) -> usize {
+        match self.p.checked_add_signed(offset) {
+            Some(i) if i < TAPE_SIZE => i,
+            _ => self.fail("Runtime: Pointer overflow"),
+        }
+    }
+
+    fn cell(&self) -> Cell {
+        self.tape[self.p]
+    }
+
+    fn shift(&mut self, offset: isize) {
+        self.p = self.at(offset);
+    }
+
+    fn add(&mut self, offset: isize, val: Cell) {
+        let i = self.at(offset);
+        self.tape[i] = self.tape[i].wrapping_add(val);
+    }
+
+    fn sub(&mut self, offset: isize, val: Cell) {
+        let i = self.at(offset);
+        self.tape[i] = self.tape[i].wrapping_sub(val);
+    }
+
+    fn set_zero(&mut self) {
+        self.tape[self.p] = 0;
+    }
+
+    fn set(&mut self, val: Cell) {
+        self.tape[self.p] = val;
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: Cell) {
+        if self.cell() != 0 {
+            let i = self.at(offset);
+            self.tape[i] = self.tape[i].wrapping_add(self.cell().wrapping_mul(factor));
+        }
+    }
+
+    fn scan(&mut self, stride: isize) {
+        while self.cell() != 0 {
+            self.shift(stride);
+        }
+    }
+
+    fn put(&mut self, offset: isize) {
+        let i = self.at(offset);
+        if self.output.write_all(&[self.tape[i] as u8]).is_err() {
+            self.fail("Runtime: IO error");
+        }
+    }
+"#;
+
+/// Translates `ir` to a standalone C program with the tape size, cell width,
+/// tape origin and EOF behaviour of `config`.
+pub fn to_c(ir: &[BfIR], config: &VmConfig) -> Result<String> {
+    config.validate().map_err(VMError::InvalidConfig)?;
+    check_loops(ir)?;
+
+    let cell = match config.cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    };
+    let on_eof = match config.eof {
+        EofPolicy::Unchanged => "",
+        EofPolicy::Zero => "\n    else tape[i] = 0;",
+        EofPolicy::MinusOne => "\n    else tape[i] = (cell)-1;",
+    };
+
+    let mut out = String::new();
+    writeln!(out, "/* Generated by bfrs */").unwrap();
+    writeln!(out, "#include <stdint.h>").unwrap();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    writeln!(out, "#include <stdlib.h>\n").unwrap();
+    writeln!(out, "#define TAPE_SIZE {}LL", config.tape_size).unwrap();
+    writeln!(out, "#define TAPE_ORIGIN {}", config.tape_origin).unwrap();
+    writeln!(out, "typedef {} cell;\n", cell).unwrap();
+    out += C_PRELUDE;
+    writeln!(
+        out,
+        r#"
+static void get(long long offset) {{
+    size_t i = at(offset);
+    fflush(stdout);
+    int c = getchar();
+    if (c != EOF) tape[i] = (cell)c;
+    else if (ferror(stdin)) fail("Runtime: IO error");{}
+}}
+
+int main(void) {{"#,
+        on_eof
+    )
+    .unwrap();
+    write_body(&mut out, ir, config.cell_width, "", "while (tape[p]) {");
+    writeln!(
+        out,
+        r#"    if (fflush(stdout)) fail("Runtime: IO error");
+    return 0;
+}}"#
+    )
+    .unwrap();
+
+    Ok(out)
+}
+
+/// Translates `ir` to a standalone Rust program with the tape size, cell
+/// width, tape origin and EOF behaviour of `config`.
+pub fn to_rust(ir: &[BfIR], config: &VmConfig) -> Result<String> {
+    config.validate().map_err(VMError::InvalidConfig)?;
+    check_loops(ir)?;
+
+    let cell = match config.cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    };
+    let on_eof = match config.eof {
+        EofPolicy::Unchanged => "{}",
+        EofPolicy::Zero => "self.tape[i] = 0,",
+        EofPolicy::MinusOne => "self.tape[i] = Cell::MAX,",
+    };
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by bfrs").unwrap();
+    writeln!(out, "#![allow(dead_code)]\n").unwrap();
+    writeln!(out, "const TAPE_SIZE: usize = {};", config.tape_size).unwrap();
+    writeln!(out, "const TAPE_ORIGIN: usize = {};", config.tape_origin).unwrap();
+    writeln!(out, "type Cell = {};\n", cell).unwrap();
+    out += RUST_PRELUDE;
+    writeln!(
+        out,
+        r#"
+    fn get(&mut self, offset: isize) {{
+        let i = self.at(offset);
+        if self.output.flush().is_err() {{
+            self.fail("Runtime: IO error");
+        }}
+        let mut buf = [0];
+        match std::io::stdin().read(&mut buf) {{
+            Ok(0) => {}
+            Ok(_) => self.tape[i] = buf[0] as Cell,
+            Err(_) => self.fail("Runtime: IO error"),
+        }}
+    }}
+}}
+
+fn main() {{
+    let mut vm = Vm {{
+        tape: vec![0; TAPE_SIZE],
+        p: TAPE_ORIGIN,
+        output: BufWriter::new(std::io::stdout()),
+    }};"#,
+        on_eof
+    )
+    .unwrap();
+    write_body(
+        &mut out,
+        ir,
+        config.cell_width,
+        "vm.",
+        "while vm.cell() != 0 {",
+    );
+    writeln!(
+        out,
+        r#"    if vm.output.flush().is_err() {{
+        vm.fail("Runtime: IO error");
+    }}
+}}"#
+    )
+    .unwrap();
+
+    Ok(out)
+}
+
+/// Writes one statement per op, calling the helpers through `recv` and
+/// opening loops with `open`.
+fn write_body(out: &mut String, ir: &[BfIR], width: CellWidth, recv: &str, open: &str) {
+    // values wrap at the cell width
+    let cell = |x: u32| match width {
+        CellWidth::U8 => x as u8 as u32,
+        CellWidth::U16 => x as u16 as u32,
+        CellWidth::U32 => x,
+    };
+
+    let mut depth = 1;
+    use BfIR::*;
+    for &op in ir {
+        if op == Jnz {
+            depth -= 1;
+        }
+        let stmt = match op {
+            AddPtr(x) => format!("{}shift({});", recv, x),
+            SubPtr(x) => format!("{}shift(-{});", recv, x),
+            AddVal { offset, val } => format!("{}add({}, {});", recv, offset, cell(val)),
+            SubVal { offset, val } => format!("{}sub({}, {});", recv, offset, cell(val)),
+            GetByte { offset } => format!("{}get({});", recv, offset),
+            PutByte { offset } => format!("{}put({});", recv, offset),
+            Jz => open.to_string(),
+            Jnz => "}".to_string(),
+            SetZero => format!("{}set_zero();", recv),
+            SetValue(val) => format!("{}set({});", recv, cell(val)),
+            MulAdd { offset, factor } => {
+                format!("{}mul_add({}, {});", recv, offset, cell(factor))
+            }
+            ScanRight(x) => format!("{}scan({});", recv, x),
+            ScanLeft(x) => format!("{}scan(-{});", recv, x),
+        };
+        writeln!(out, "{:width$}{}", "", stmt, width = 4 * depth).unwrap();
+        if op == Jz {
+            depth += 1;
+        }
+    }
+}
+
+#[test]
+fn test_transpile() {
+    use crate::bfio::{output_to, run_exe, scratch_dir};
+    use std::process::Command;
+
+    let tmp = scratch_dir();
+    let dir = tmp.path();
+
+    let cases = [
+        (
+            "++++++++[>++++++++<-]>+.+.+.[>]<[<]",
+            VmConfig::new(),
+            &b""[..],
+        ),
+        (
+            ",[.,]-.",
+            VmConfig::new()
+                .cell_width(CellWidth::U16)
+                .eof(EofPolicy::Zero),
+            b"cat",
+        ),
+        (
+            ",+[-[-]+.,+]",
+            VmConfig::new()
+                .cell_width(CellWidth::U32)
+                .eof(EofPolicy::MinusOne),
+            b"ab",
+        ),
+        (
+            "+.<<<",
+            VmConfig::new()
+                .tape_size(8)
+                .tape_origin(2)
+                .eof(EofPolicy::Zero),
+            b"",
+        ),
+    ];
+
+    for (i, (src, config, input)) in cases.into_iter().enumerate() {
+        let mut ir = crate::bfir::compile(src).unwrap();
+        crate::bfir::optimize(&mut ir);
+
+        let mut expected = vec![];
+        let ret = crate::BfVM::from_ir(
+            ir.clone(),
+            Box::new(input),
+            output_to(&mut expected),
+            config.clone(),
+        )
+        .and_then(|mut vm| vm.run());
+
+        let c = dir.join(format!("{}.c", i));
+        std::fs::write(&c, to_c(&ir, &config).unwrap()).unwrap();
+        let rs = dir.join(format!("{}.rs", i));
+        std::fs::write(&rs, to_rust(&ir, &config).unwrap()).unwrap();
+
+        let c_exe = dir.join(format!("{}-c", i));
+        let rs_exe = dir.join(format!("{}-rs", i));
+        let status = Command::new("cc")
+            .arg("-O")
+            .arg(&c)
+            .arg("-o")
+            .arg(&c_exe)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = Command::new("rustc")
+            .arg(&rs)
+            .arg("-o")
+            .arg(&rs_exe)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        for exe in [c_exe, rs_exe] {
+            let out = run_exe(&exe, input);
+            assert_eq!(out.stdout, expected, "{}", src);
+            assert_eq!(out.status.success(), ret.is_ok(), "{}", src);
+        }
+    }
+}